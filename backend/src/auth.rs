@@ -0,0 +1,64 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{error::AppError, AppState};
+
+pub const ADMIN_COOKIE: &str = "admin_token";
+
+/// Placeholder returned instead of secrets by `GET /api/config`. Posting it
+/// back leaves the stored value untouched.
+pub const REDACTED: &str = "********";
+
+/// Token from `Authorization: Bearer ...`, if present.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Compares secrets without short-circuiting on the first differing byte.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether the request carries the configured admin token.
+pub async fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let expected = state.config.read().await.admin_token.clone();
+    if expected.is_empty() {
+        return false;
+    }
+    bearer_token(headers)
+        .into_iter()
+        .chain(cookie_value(headers, ADMIN_COOKIE))
+        .any(|token| constant_time_eq(token, &expected))
+}
+
+/// Guards config and destructive history routes behind `Config::admin_token`.
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !is_admin(&state, request.headers()).await {
+        return Err(AppError::Unauthorized("A valid admin token is required".to_string()));
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,130 @@
+//! Chat completion proxy behind `/api/chat` (Agent page) and the
+//! OpenAI-compatible `/v1/chat/completions`. With `stream: true` the
+//! upstream's SSE chunks are relayed as they arrive.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+
+use crate::{error::AppError, extract::Json, keys::ClientIdentity, models, AppState};
+
+/// `/api/chat`, used by the Agent page.
+pub async fn chat_completions(
+    State(state): State<AppState>,
+    Json(payload): Json<models::openai::ChatCompletionRequest>,
+) -> Result<Response, AppError> {
+    proxy_chat(&state, payload).await
+}
+
+/// `/v1/chat/completions` for OpenAI SDKs. Counts against the key's
+/// request rate limit; chat does not use the image quota.
+pub async fn openai_chat_completions(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    Json(payload): Json<models::openai::ChatCompletionRequest>,
+) -> Result<Response, AppError> {
+    if let Some(key_id) = identity.key_id.as_deref() {
+        let limits = state.config.read().await.limits_for(key_id);
+        state.quota.acquire(key_id, &limits, 0).await?;
+    }
+    tracing::info!("收到聊天请求 [{}]: {}", identity.name, payload.model);
+    proxy_chat(&state, payload).await
+}
+
+/// Forwards a chat completion with the same retry, circuit breaker and
+/// upstream fallback as image generation. Retries only happen before the
+/// response starts, so a stream is never replayed.
+async fn proxy_chat(state: &AppState, mut payload: models::openai::ChatCompletionRequest) -> Result<Response, AppError> {
+    if payload.model.is_empty() {
+        payload.model = "gemini-3-flash".to_string();
+    }
+    let settings = state.config.read().await.generation_settings();
+
+    let (resp, upstream) = crate::with_fallback(state, &payload.model, |target| {
+        let payload = &payload;
+        let settings = &settings;
+        async move { crate::send_with_retries(state, &target, payload, settings, None).await.map(|(resp, _)| resp) }
+    })
+    .await?;
+
+    if payload.stream {
+        return Ok(relay_stream(resp, upstream, Duration::from_secs(settings.timeout)));
+    }
+
+    let data = resp
+        .json::<serde_json::Value>()
+        .await
+        .inspect_err(|e| tracing::error!("解析聊天响应失败: {}", e))?;
+    Ok(Json(data).into_response())
+}
+
+/// Passes the upstream event stream through untouched. A failure mid-stream,
+/// or no data for `idle_timeout`, is reported as a final
+/// `data: {"error": ...}` event, since the status line has already been sent.
+fn relay_stream(resp: reqwest::Response, upstream: String, idle_timeout: Duration) -> Response {
+    let guard = StreamGuard { upstream, finished: false };
+    let body = futures::stream::unfold(
+        (resp.bytes_stream().boxed(), guard),
+        move |(mut chunks, mut guard)| async move {
+            if guard.finished {
+                return None;
+            }
+            let error = match tokio::time::timeout(idle_timeout, chunks.next()).await {
+                Ok(Some(Ok(chunk))) => return Some((Ok::<_, std::convert::Infallible>(chunk), (chunks, guard))),
+                Ok(Some(Err(e))) => {
+                    tracing::error!("上游 {} 流式响应中断: {}", guard.upstream, e);
+                    AppError::from(e)
+                }
+                Ok(None) => {
+                    guard.finish();
+                    return None;
+                }
+                Err(_) => {
+                    tracing::error!("上游 {} 流式响应 {}s 内无数据，已中止", guard.upstream, idle_timeout.as_secs());
+                    AppError::Timeout(format!("Upstream stream stalled for {}s", idle_timeout.as_secs()))
+                }
+            };
+            guard.finish();
+            let event = format!("data: {}\n\n", error.body());
+            Some((Ok(Bytes::from(event)), (chunks, guard)))
+        },
+    );
+    sse_response(body)
+}
+
+fn sse_response(body: impl Stream<Item = Result<Bytes, std::convert::Infallible>> + Send + 'static) -> Response {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        // Keep reverse proxies such as nginx from buffering the stream.
+        .header("X-Accel-Buffering", "no")
+        .body(Body::from_stream(body))
+        .unwrap()
+}
+
+/// Logs streams dropped before the upstream finished, i.e. the client went
+/// away. Dropping the stream also drops the upstream connection.
+struct StreamGuard {
+    upstream: String,
+    finished: bool,
+}
+
+impl StreamGuard {
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::info!("客户端已断开，停止转发上游 {} 的流式响应", self.upstream);
+        }
+    }
+}
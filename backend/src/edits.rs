@@ -0,0 +1,227 @@
+//! OpenAI-style multipart image endpoints. Uploaded files become data URLs
+//! in an [`ImageGenerationRequest`](openai::ImageGenerationRequest), so they
+//! go through the same admission, retries and history as JSON requests.
+
+use axum::extract::{multipart::Field, Extension, State};
+
+use crate::{
+    error::AppError,
+    extract::{Json, Multipart},
+    keys::ClientIdentity,
+    models::openai,
+    references,
+    storage,
+    AppState,
+};
+
+/// Text fields are small; anything longer is almost certainly a mistake.
+const MAX_TEXT_FIELD_BYTES: u64 = 64 * 1024;
+
+/// Generation fields are few; this leaves room for unknown OpenAI ones.
+const MAX_TEXT_FIELDS: usize = 32;
+
+/// Request body cap for the multipart routes: `max_request_bytes` of files,
+/// the text fields, and room for multipart framing.
+pub fn body_limit(max_request_bytes: u64) -> usize {
+    let text = MAX_TEXT_FIELDS as u64 * MAX_TEXT_FIELD_BYTES;
+    usize::try_from(max_request_bytes.saturating_add(text).saturating_add(64 * 1024)).unwrap_or(usize::MAX)
+}
+
+const VARIATION_PROMPT: &str = "Create a new variation of the reference image. \
+Keep its subject, overall composition, style and color palette, but vary the details so it is clearly a different image.";
+
+/// An `image`/`mask` field: an uploaded file, or (as a plain text field) a
+/// reference to an image already in `storage_path`.
+enum FormImage {
+    Upload(Vec<u8>),
+    Stored(String),
+}
+
+impl FormImage {
+    /// Stored images stay references, so history records the file rather
+    /// than a copy of it.
+    fn source(&self) -> Result<String, AppError> {
+        match self {
+            FormImage::Upload(bytes) => storage::to_data_url(bytes),
+            FormImage::Stored(filename) => Ok(format!("/images/{}", filename)),
+        }
+    }
+}
+
+/// A parsed multipart form: the images, plus the text fields as a JSON
+/// object ready to deserialize with the request defaults.
+#[derive(Default)]
+struct ImageForm {
+    images: Vec<FormImage>,
+    mask: Option<FormImage>,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ImageForm {
+    async fn read(Multipart(mut multipart): Multipart, storage_path: &str, max_upload_bytes: u64) -> Result<Self, AppError> {
+        let mut form = ImageForm::default();
+        while let Some(field) = multipart.next_field().await.map_err(bad_multipart)? {
+            let name = field.name().unwrap_or_default().to_string();
+            match name.as_str() {
+                "image" | "image[]" => {
+                    if form.images.len() == references::MAX_REFERENCES {
+                        return Err(AppError::BadRequest(format!("At most {} images can be uploaded", references::MAX_REFERENCES)));
+                    }
+                    form.images.push(read_image(field, storage_path, max_upload_bytes).await?);
+                }
+                "mask" => form.mask = Some(read_image(field, storage_path, max_upload_bytes).await?),
+                _ => {
+                    if form.fields.len() == MAX_TEXT_FIELDS {
+                        return Err(AppError::BadRequest(format!("At most {} form fields are accepted", MAX_TEXT_FIELDS)));
+                    }
+                    let text = read_text(field).await?;
+                    let value = match name.as_str() {
+                        "n" => serde_json::json!(text
+                            .trim()
+                            .parse::<usize>()
+                            .map_err(|_| AppError::BadRequest(format!("n must be an integer, got {:?}", text)))?),
+                        _ => serde_json::Value::String(text),
+                    };
+                    form.fields.insert(name, value);
+                }
+            }
+        }
+        Ok(form)
+    }
+
+    /// Builds the generation request; unknown fields (`user`, `quality`, ...)
+    /// are ignored the same way the JSON endpoint ignores them.
+    fn into_request(self) -> Result<openai::ImageGenerationRequest, AppError> {
+        let mut request: openai::ImageGenerationRequest = serde_json::from_value(serde_json::Value::Object(self.fields))
+            .map_err(|e| AppError::BadRequest(format!("Invalid form: {}", e)))?;
+        request.image = None;
+        request.images = Some(self.images.iter().map(FormImage::source).collect::<Result<_, _>>()?);
+        request.mask = self.mask.as_ref().map(FormImage::source).transpose()?;
+        Ok(request)
+    }
+}
+
+/// Keeps the status of body errors, so hitting the body limit is a 413.
+fn bad_multipart(e: axum::extract::multipart::MultipartError) -> AppError {
+    AppError::Rejected { status: e.status().as_u16(), message: format!("Invalid multipart body: {}", e.body_text()) }
+}
+
+async fn read_limited(mut field: Field<'_>, max_bytes: u64) -> Result<Vec<u8>, AppError> {
+    let name = field.name().unwrap_or_default().to_string();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(bad_multipart)? {
+        if (bytes.len() + chunk.len()) as u64 > max_bytes {
+            return Err(AppError::BadRequest(format!("Field {} exceeds the limit of {} bytes", name, max_bytes)));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+async fn read_text(field: Field<'_>) -> Result<String, AppError> {
+    let name = field.name().unwrap_or_default().to_string();
+    String::from_utf8(read_limited(field, MAX_TEXT_FIELD_BYTES).await?)
+        .map_err(|_| AppError::BadRequest(format!("Field {} is not valid UTF-8", name)))
+}
+
+/// File parts are uploads, checked to be an image by their bytes rather than
+/// the client's content type; text parts are storage references.
+async fn read_image(field: Field<'_>, storage_path: &str, max_bytes: u64) -> Result<FormImage, AppError> {
+    let name = field.name().unwrap_or_default().to_string();
+    if field.file_name().is_none() {
+        let reference = read_text(field).await?;
+        return Ok(FormImage::Stored(storage::find_stored(storage_path, &reference).await?));
+    }
+
+    let bytes = read_limited(field, max_bytes).await?;
+    if bytes.is_empty() {
+        return Err(AppError::BadRequest(format!("Field {} is empty", name)));
+    }
+    storage::sniff_format(&bytes).map_err(|e| AppError::BadRequest(format!("Field {}: {}", name, e.message())))?;
+    Ok(FormImage::Upload(bytes))
+}
+
+/// `POST /v1/images/edits`: multipart `image` (or several `image[]`), an
+/// optional `mask`, `prompt` and the usual generation fields.
+pub async fn edit_image(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    multipart: Multipart,
+) -> Result<Json<openai::ImageResponse>, AppError> {
+    let (storage_path, max_upload_bytes) = {
+        let config = state.config.read().await;
+        (config.storage_path.clone(), config.max_upload_bytes)
+    };
+    let form = ImageForm::read(multipart, &storage_path, max_upload_bytes).await?;
+    if form.images.is_empty() {
+        return Err(AppError::BadRequest("An image to edit is required".to_string()));
+    }
+    if !form.fields.contains_key("prompt") {
+        return Err(AppError::BadRequest("A prompt describing the edit is required".to_string()));
+    }
+    let payload = form.into_request()?;
+
+    tracing::info!(
+        "收到图像编辑请求 [{}]: {} ({} 张参考图{})",
+        identity.name,
+        payload.prompt,
+        payload.images.as_ref().map_or(0, Vec::len),
+        if payload.mask.is_some() { "，含蒙版" } else { "" }
+    );
+    let data = crate::generate_now(&state, &identity, &payload, None).await?;
+    Ok(Json(data))
+}
+
+/// `POST /v1/images/variations`: one `image`, uploaded or referenced by
+/// `/images/<file>` or id. An optional `prompt` steers the variation. The
+/// source is kept in storage and recorded on the resulting history entry.
+pub async fn create_variation(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    multipart: Multipart,
+) -> Result<Json<openai::ImageResponse>, AppError> {
+    let (storage_path, max_upload_bytes) = {
+        let config = state.config.read().await;
+        (config.storage_path.clone(), config.max_upload_bytes)
+    };
+    let mut form = ImageForm::read(multipart, &storage_path, max_upload_bytes).await?;
+    if form.images.len() != 1 || form.mask.is_some() {
+        return Err(AppError::BadRequest("Variations take exactly one image and no mask".to_string()));
+    }
+
+    let guidance = form.fields.remove("prompt").and_then(|p| p.as_str().map(str::trim).map(str::to_string));
+    let prompt = match guidance.filter(|g| !g.is_empty()) {
+        Some(guidance) => format!("{}\n{}", VARIATION_PROMPT, guidance),
+        None => VARIATION_PROMPT.to_string(),
+    };
+    form.fields.insert("prompt".to_string(), serde_json::Value::String(prompt));
+
+    // Uploads are stored first so the lineage points at a file we keep, and
+    // removed again if the variation is rejected or fails. They are stored by
+    // content, so uploading the same image again reuses its file.
+    let uploaded = matches!(form.images[0], FormImage::Upload(_));
+    if let FormImage::Upload(bytes) = &form.images[0] {
+        form.images[0] = FormImage::Stored(storage::write_image_deduplicated(&storage_path, bytes).await?.filename);
+    }
+    let source_url = form.images[0].source()?;
+
+    let result = match form.into_request() {
+        Ok(payload) => {
+            tracing::info!("收到图像变体请求 [{}]: {} (n = {})", identity.name, source_url, payload.n);
+            crate::generate_now(&state, &identity, &payload, Some(source_url.clone())).await
+        }
+        Err(e) => Err(e),
+    };
+    // The file may be an earlier upload of the same image that history uses.
+    if result.is_err() && uploaded {
+        let removed = match state.history.uses(&source_url).await {
+            Ok(true) => Ok(()),
+            Ok(false) => storage::remove_stored(&storage_path, &source_url).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = removed {
+            tracing::warn!("删除未使用的变体源图失败: {}", e);
+        }
+    }
+    Ok(Json(result?))
+}
@@ -0,0 +1,249 @@
+use axum::{
+    extract::{
+        multipart::MultipartRejection,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// `Retry-After` for an upstream 429 that came without a hint, in seconds.
+const UPSTREAM_RETRY_AFTER: u64 = 30;
+
+/// Errors surfaced by handlers, rendered as OpenAI-style
+/// `{"error": {"message", "type", "code"}}` bodies.
+#[derive(Debug, Clone)]
+pub enum AppError {
+    /// The client sent something we cannot act on.
+    BadRequest(String),
+    /// An extractor refused the request before the handler ran; keeps its
+    /// status (400, 413, 415, 422, ...).
+    Rejected { status: u16, message: String },
+    /// Missing or invalid credentials.
+    Unauthorized(String),
+    /// A client rate limit or quota was hit; `retry_after` is in seconds.
+    RateLimited { message: String, retry_after: u64 },
+    NotFound(String),
+    /// The upstream answered with a 4xx status. `retry_after` is the
+    /// upstream's hint in seconds, kept for a 429 that outlasted our retries.
+    UpstreamClient { status: u16, message: String, retry_after: Option<u64> },
+    /// The upstream answered with a 5xx status.
+    UpstreamServer { status: u16, message: String },
+    /// The upstream could not be reached at all.
+    UpstreamUnavailable(String),
+    Timeout(String),
+    /// The upstream answered, but not with something we could understand.
+    Parse(String),
+    Storage(String),
+    Config(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Rejected { status, .. } => StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_REQUEST),
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            // Our own upstream credentials being rejected is a gateway problem,
+            // not something the caller can fix.
+            AppError::UpstreamClient { status: 401 | 403, .. } => StatusCode::BAD_GATEWAY,
+            AppError::UpstreamClient { status, .. } => {
+                StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY)
+            }
+            AppError::UpstreamServer { .. } | AppError::UpstreamUnavailable(_) | AppError::Parse(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Storage(_) | AppError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) | AppError::Rejected { .. } => "invalid_request_error",
+            AppError::Unauthorized(_) => "authentication_error",
+            AppError::RateLimited { .. } => "rate_limit_error",
+            AppError::NotFound(_) => "not_found_error",
+            AppError::UpstreamClient { .. }
+            | AppError::UpstreamServer { .. }
+            | AppError::UpstreamUnavailable(_) => "upstream_error",
+            AppError::Timeout(_) => "timeout_error",
+            AppError::Parse(_) => "upstream_parse_error",
+            AppError::Storage(_) => "storage_error",
+            AppError::Config(_) => "config_error",
+        }
+    }
+
+    pub fn code(&self) -> String {
+        match self {
+            AppError::UpstreamClient { status, .. } | AppError::UpstreamServer { status, .. } => {
+                format!("upstream_{}", status)
+            }
+            AppError::UpstreamUnavailable(_) => "upstream_unavailable".to_string(),
+            AppError::BadRequest(_) => "invalid_request".to_string(),
+            AppError::Rejected { status: 413, .. } => "payload_too_large".to_string(),
+            AppError::Rejected { status: 415, .. } => "unsupported_media_type".to_string(),
+            AppError::Rejected { status: 422, .. } => "unprocessable_entity".to_string(),
+            AppError::Rejected { .. } => "invalid_request".to_string(),
+            AppError::Unauthorized(_) => "invalid_token".to_string(),
+            AppError::RateLimited { .. } => "rate_limit_exceeded".to_string(),
+            AppError::NotFound(_) => "not_found".to_string(),
+            AppError::Timeout(_) => "timeout".to_string(),
+            AppError::Parse(_) => "parse_failure".to_string(),
+            AppError::Storage(_) => "storage_failure".to_string(),
+            AppError::Config(_) => "config_failure".to_string(),
+        }
+    }
+
+    /// Whether trying the same request again (here or on another upstream) may help.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::UpstreamClient { status, .. } => *status == 429,
+            AppError::UpstreamServer { .. } | AppError::UpstreamUnavailable(_) | AppError::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(m)
+            | AppError::Unauthorized(m)
+            | AppError::NotFound(m)
+            | AppError::UpstreamUnavailable(m)
+            | AppError::Timeout(m)
+            | AppError::Parse(m)
+            | AppError::Storage(m)
+            | AppError::Config(m) => m,
+            AppError::Rejected { message, .. }
+            | AppError::UpstreamClient { message, .. }
+            | AppError::UpstreamServer { message, .. }
+            | AppError::RateLimited { message, .. } => message,
+        }
+    }
+
+    /// OpenAI-style `{"error": {...}}` body, also used for errors reported
+    /// inside an SSE stream.
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "message": self.message(),
+                "type": self.error_type(),
+                "code": self.code(),
+            }
+        })
+    }
+
+    /// Classifies a non-success upstream status.
+    pub fn from_upstream_status(status: reqwest::StatusCode, body: &str) -> Self {
+        let message = format!("Upstream returned {}: {}", status, body);
+        if status.is_client_error() {
+            AppError::UpstreamClient { status: status.as_u16(), message, retry_after: None }
+        } else {
+            AppError::UpstreamServer { status: status.as_u16(), message }
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            AppError::Timeout(format!("Upstream request timed out: {}", e))
+        } else if e.is_decode() {
+            AppError::Parse(format!("Failed to decode upstream response: {}", e))
+        } else if let Some(status) = e.status() {
+            AppError::from_upstream_status(status, &e.to_string())
+        } else {
+            AppError::UpstreamUnavailable(format!("Failed to reach upstream: {}", e))
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Storage(format!("History database error: {}", e))
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(e: JsonRejection) -> Self {
+        AppError::Rejected { status: e.status().as_u16(), message: e.body_text() }
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(e: QueryRejection) -> Self {
+        AppError::Rejected { status: e.status().as_u16(), message: e.body_text() }
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(e: PathRejection) -> Self {
+        AppError::Rejected { status: e.status().as_u16(), message: e.body_text() }
+    }
+}
+
+impl From<MultipartRejection> for AppError {
+    fn from(e: MultipartRejection) -> Self {
+        AppError::Rejected { status: e.status().as_u16(), message: e.body_text() }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        let retry_after = match self {
+            AppError::RateLimited { retry_after, .. } => Some(retry_after),
+            AppError::UpstreamClient { status: 429, retry_after, .. } => {
+                Some(retry_after.unwrap_or(UPSTREAM_RETRY_AFTER))
+            }
+            _ => None,
+        };
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_after(error: AppError) -> Option<String> {
+        let response = error.into_response();
+        response.headers().get(header::RETRY_AFTER).map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn upstream_rate_limit_carries_retry_after() {
+        let hinted = AppError::UpstreamClient { status: 429, message: "slow down".to_string(), retry_after: Some(7) };
+        assert_eq!(hinted.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(retry_after(hinted).as_deref(), Some("7"));
+
+        let unhinted = AppError::from_upstream_status(reqwest::StatusCode::TOO_MANY_REQUESTS, "slow down");
+        assert_eq!(retry_after(unhinted), Some(UPSTREAM_RETRY_AFTER.to_string()));
+
+        let rejected = AppError::from_upstream_status(reqwest::StatusCode::BAD_REQUEST, "bad prompt");
+        assert_eq!(retry_after(rejected), None);
+    }
+
+    #[test]
+    fn rejections_keep_their_status() {
+        let error = AppError::Rejected { status: 413, message: "too large".to_string() };
+        assert_eq!(error.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error.code(), "payload_too_large");
+        assert_eq!(error.error_type(), "invalid_request_error");
+    }
+}
@@ -0,0 +1,829 @@
+//! Generation history, stored in SQLite (`history.db` next to
+//! `config.json`). Each group is written in one transaction together with
+//! its images. A `history.json` left by older versions is imported once when
+//! the database is created, then renamed to `history.json.migrated`.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+const DB_FILE: &str = "history.db";
+const LEGACY_FILE: &str = "history.json";
+
+/// Bumped whenever `migrate` learns a new step.
+const SCHEMA_VERSION: i64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GenerationGroup {
+    /// UUID; assigned on insert when empty.
+    #[serde(default)]
+    pub id: String,
+    pub prompt: String,
+    pub timestamp: u64,
+    pub images: Vec<String>,
+    /// Client API key that requested the generation; `None` for admin/UI calls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key_id: Option<String>,
+    /// Image this generation was a variation of, as `/images/<file>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_image: Option<String>,
+    // What was asked for and how it was produced. Groups recorded before
+    // these were tracked leave them empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    /// Reference images: `/images/<file>` for stored and uploaded ones,
+    /// otherwise the URL the upstream was given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<String>,
+    /// Upstream that produced the images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// Upstream requests made, counting retries and upstreams that failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<usize>,
+    /// Milliseconds from the start of generation until the images were saved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Per-image ids and details, in the same order as `images`.
+    #[serde(default)]
+    pub image_details: Vec<ImageRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImageRecord {
+    /// See [`image_id`]; assigned on insert when empty.
+    #[serde(default)]
+    pub id: String,
+    pub url: String,
+    /// The upstream's text reply for this image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revised_prompt: Option<String>,
+    /// Requests made on the upstream that produced it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+/// Filters for `GET /api/history`. Without any, every group is returned,
+/// newest first.
+#[derive(Deserialize, Default, Debug)]
+pub struct HistoryQuery {
+    pub limit: Option<u32>,
+    /// Only groups created before this timestamp (ms), for paging.
+    pub before: Option<u64>,
+    /// Only groups whose prompt starts with this text, ignoring ASCII case.
+    pub prompt: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct HistoryStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl HistoryStore {
+    pub fn open() -> Result<Self, AppError> {
+        let mut conn = Connection::open(DB_FILE)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        let imported = migrate(&mut conn, Path::new(LEGACY_FILE))?;
+        if imported > 0 {
+            tracing::info!("已从 {} 导入 {} 条历史记录", LEGACY_FILE, imported);
+            let migrated = format!("{}.migrated", LEGACY_FILE);
+            if let Err(e) = std::fs::rename(LEGACY_FILE, &migrated) {
+                tracing::warn!("重命名 {} 失败: {}", LEGACY_FILE, e);
+            }
+        }
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Runs `f` on the connection off the async runtime.
+    async fn run<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| AppError::Storage(format!("History task failed: {}", e)))?
+        .map_err(AppError::from)
+    }
+
+    pub async fn list(&self, query: HistoryQuery) -> Result<Vec<GenerationGroup>, AppError> {
+        self.run(move |conn| {
+            // Conditions are only added when set, so SQLite can pick the
+            // timestamp or prompt index for them.
+            let mut conditions = vec!["1"];
+            let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+            if let Some(before) = query.before {
+                conditions.push("timestamp < ?");
+                values.push(Box::new(before));
+            }
+            if let Some(prompt) = query.prompt {
+                conditions.push("prompt LIKE ? ESCAPE '\\'");
+                values.push(Box::new(like_prefix(&prompt)));
+            }
+            values.push(Box::new(query.limit.map_or(-1, i64::from)));
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM history_groups WHERE {} ORDER BY timestamp DESC, id DESC LIMIT ?",
+                GROUP_COLUMNS,
+                conditions.join(" AND ")
+            ))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(values), read_group)?;
+            let groups = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+            groups.into_iter().map(|(id, group)| with_images(conn, id, group)).collect()
+        })
+        .await
+    }
+
+    pub async fn find(&self, id: &str) -> Result<Option<GenerationGroup>, AppError> {
+        let id = id.to_string();
+        self.run(move |conn| {
+            let group = conn
+                .prepare_cached(&format!("SELECT {} FROM history_groups WHERE uuid = ?1", GROUP_COLUMNS))?
+                .query_row(params![id], read_group)
+                .optional()?;
+            group.map(|(id, group)| with_images(conn, id, group)).transpose()
+        })
+        .await
+    }
+
+    /// For clients from before groups had ids. Several groups can share a
+    /// millisecond; the most recently inserted one wins.
+    pub async fn find_by_timestamp(&self, timestamp: u64) -> Result<Option<GenerationGroup>, AppError> {
+        self.run(move |conn| {
+            let group = conn
+                .prepare_cached(&format!(
+                    "SELECT {} FROM history_groups WHERE timestamp = ?1 ORDER BY id DESC LIMIT 1",
+                    GROUP_COLUMNS
+                ))?
+                .query_row(params![timestamp], read_group)
+                .optional()?;
+            group.map(|(id, group)| with_images(conn, id, group)).transpose()
+        })
+        .await
+    }
+
+    pub async fn insert(&self, group: GenerationGroup) -> Result<(), AppError> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            insert_group(&tx, &group)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    /// Removes a group with its images. Returns the stored files it used
+    /// (images, references, mask, variation source) that no remaining group
+    /// uses, or `None` if there was no such group.
+    pub async fn delete_group(&self, id: &str) -> Result<Option<Vec<String>>, AppError> {
+        let id = id.to_string();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(rowid) = tx
+                .query_row("SELECT id FROM history_groups WHERE uuid = ?1", params![id], |row| row.get::<_, i64>(0))
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            let files = group_files(&tx, rowid)?;
+            // Images and references go with it (ON DELETE CASCADE).
+            tx.execute("DELETE FROM history_groups WHERE id = ?1", params![rowid])?;
+            let files = unreferenced(&tx, files)?;
+            tx.commit()?;
+            Ok(Some(files))
+        })
+        .await
+    }
+
+    /// Removes one image, and its group if that was the last image. Returns
+    /// the stored files no remaining group uses: the image's, plus the
+    /// group's own if it went too. `None` if there was no such image.
+    pub async fn delete_image(&self, id: &str) -> Result<Option<Vec<String>>, AppError> {
+        let id = id.to_string();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let Some((group_id, url)) = tx
+                .query_row("SELECT group_id, url FROM history_images WHERE uuid = ?1", params![id], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            tx.execute("DELETE FROM history_images WHERE uuid = ?1", params![id])?;
+            let mut files = vec![url];
+            let last_image: bool = tx.query_row(
+                "SELECT NOT EXISTS (SELECT 1 FROM history_images WHERE group_id = ?1)",
+                params![group_id],
+                |row| row.get(0),
+            )?;
+            if last_image {
+                files.extend(group_files(&tx, group_id)?);
+                tx.execute("DELETE FROM history_groups WHERE id = ?1", params![group_id])?;
+            }
+            let files = unreferenced(&tx, files)?;
+            tx.commit()?;
+            Ok(Some(files))
+        })
+        .await
+    }
+
+    /// Whether any group uses the stored file behind `url`.
+    pub async fn uses(&self, url: &str) -> Result<bool, AppError> {
+        let url = url.to_string();
+        self.run(move |conn| in_use(conn, &url)).await
+    }
+
+    /// Removes every group. Returns every stored file they used.
+    pub async fn clear(&self) -> Result<Vec<String>, AppError> {
+        self.run(|conn| {
+            let tx = conn.transaction()?;
+            let files = stored_files(
+                &tx,
+                "SELECT url FROM history_images
+                 UNION SELECT source FROM history_references
+                 UNION SELECT mask FROM history_groups WHERE mask IS NOT NULL
+                 UNION SELECT source_image FROM history_groups WHERE source_image IS NOT NULL",
+                [],
+            )?;
+            tx.execute("DELETE FROM history_references", [])?;
+            tx.execute("DELETE FROM history_images", [])?;
+            tx.execute("DELETE FROM history_groups", [])?;
+            tx.commit()?;
+            Ok(files)
+        })
+        .await
+    }
+}
+
+/// Brings the schema up to date. Returns how many groups were imported from
+/// the legacy JSON file at `legacy`.
+fn migrate(conn: &mut Connection, legacy: &Path) -> Result<usize, AppError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= SCHEMA_VERSION {
+        return Ok(0);
+    }
+
+    let tx = conn.transaction()?;
+    if version < 1 {
+        tx.execute_batch(
+            "CREATE TABLE history_groups (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                prompt TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                client_key_id TEXT,
+                source_image TEXT
+            );
+            CREATE INDEX idx_history_groups_timestamp ON history_groups (timestamp);
+            CREATE INDEX idx_history_groups_prompt ON history_groups (prompt);
+            CREATE TABLE history_images (
+                group_id INTEGER NOT NULL REFERENCES history_groups (id) ON DELETE CASCADE,
+                position INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                PRIMARY KEY (group_id, position)
+            );",
+        )?;
+    }
+    if version < 2 {
+        tx.execute_batch(
+            "ALTER TABLE history_groups ADD COLUMN negative_prompt TEXT;
+            ALTER TABLE history_groups ADD COLUMN model TEXT;
+            ALTER TABLE history_groups ADD COLUMN size TEXT;
+            ALTER TABLE history_groups ADD COLUMN mask TEXT;
+            ALTER TABLE history_groups ADD COLUMN upstream TEXT;
+            ALTER TABLE history_groups ADD COLUMN attempts INTEGER;
+            ALTER TABLE history_groups ADD COLUMN latency_ms INTEGER;
+            ALTER TABLE history_images ADD COLUMN revised_prompt TEXT;
+            ALTER TABLE history_images ADD COLUMN attempts INTEGER;
+            ALTER TABLE history_images ADD COLUMN latency_ms INTEGER;
+            CREATE TABLE history_references (
+                group_id INTEGER NOT NULL REFERENCES history_groups (id) ON DELETE CASCADE,
+                position INTEGER NOT NULL,
+                source TEXT NOT NULL,
+                PRIMARY KEY (group_id, position)
+            );",
+        )?;
+    }
+    if version < 3 {
+        tx.execute_batch(
+            "ALTER TABLE history_groups ADD COLUMN uuid TEXT;
+            ALTER TABLE history_images ADD COLUMN uuid TEXT;",
+        )?;
+        for table in ["history_groups", "history_images"] {
+            let rows = tx
+                .prepare(&format!("SELECT rowid FROM {} WHERE uuid IS NULL", table))?
+                .query_map([], |row| row.get::<_, i64>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut update = tx.prepare(&format!("UPDATE {} SET uuid = ?1 WHERE rowid = ?2", table))?;
+            for rowid in rows {
+                update.execute(params![Uuid::new_v4().to_string(), rowid])?;
+            }
+        }
+        tx.execute_batch(
+            "CREATE UNIQUE INDEX idx_history_groups_uuid ON history_groups (uuid);
+            CREATE UNIQUE INDEX idx_history_images_uuid ON history_images (uuid);",
+        )?;
+    }
+    if version < 4 {
+        // `LIKE` ignores ASCII case, so only a NOCASE index serves prefix searches.
+        tx.execute_batch(
+            "DROP INDEX idx_history_groups_prompt;
+            CREATE INDEX idx_history_groups_prompt ON history_groups (prompt COLLATE NOCASE);",
+        )?;
+    }
+    if version < 5 {
+        // Image ids become their file's stem; an id another row already has
+        // keeps its UUID.
+        let rows = tx
+            .prepare("SELECT rowid, url FROM history_images WHERE url LIKE '/images/%'")?
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut update = tx.prepare("UPDATE OR IGNORE history_images SET uuid = ?1 WHERE rowid = ?2")?;
+        for (rowid, url) in rows {
+            update.execute(params![image_id(&url), rowid])?;
+        }
+    }
+
+    // Only a brand-new database picks up the legacy file.
+    let legacy: Vec<GenerationGroup> = match std::fs::read_to_string(legacy) {
+        Ok(content) if version == 0 => match serde_json::from_str(&content) {
+            Ok(groups) => groups,
+            Err(e) => {
+                // Keep the file for a manual look, but don't refuse to start.
+                let bad = legacy.with_extension("json.bad");
+                tracing::error!("{} 无法解析，跳过导入并重命名为 {}: {}", legacy.display(), bad.display(), e);
+                if let Err(e) = std::fs::rename(legacy, &bad) {
+                    tracing::warn!("重命名 {} 失败: {}", legacy.display(), e);
+                }
+                Vec::new()
+            }
+        },
+        _ => Vec::new(),
+    };
+    // The file is newest first; insert oldest first so row ids follow age.
+    for group in legacy.iter().rev() {
+        insert_group(&tx, group)?;
+    }
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()?;
+    Ok(legacy.len())
+}
+
+fn insert_group(conn: &Connection, group: &GenerationGroup) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO history_groups (uuid, prompt, timestamp, client_key_id, source_image, negative_prompt,
+                                     model, size, mask, upstream, attempts, latency_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+    )?
+    .execute(params![
+        or_new_id(&group.id),
+        group.prompt,
+        group.timestamp,
+        group.client_key_id,
+        group.source_image,
+        group.negative_prompt,
+        group.model,
+        group.size,
+        group.mask,
+        group.upstream,
+        group.attempts,
+        group.latency_ms,
+    ])?;
+    let id = conn.last_insert_rowid();
+
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO history_images (uuid, group_id, position, url, revised_prompt, attempts, latency_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for (position, url) in group.images.iter().enumerate() {
+        // `image_details` follows `images`; pair by position, since two
+        // images can share a URL (an upstream address kept after a failed save).
+        let details = group.image_details.get(position).filter(|d| d.url == *url);
+        let image_id = match details.map_or("", |d| &d.id) {
+            "" => image_id(url),
+            id => id.to_string(),
+        };
+        // Two rows can point at one file (e.g. a legacy import); the later
+        // one gets a UUID rather than failing the whole group.
+        let taken: bool =
+            conn.query_row("SELECT EXISTS (SELECT 1 FROM history_images WHERE uuid = ?1)", params![image_id], |row| row.get(0))?;
+        stmt.execute(params![
+            if taken { Uuid::new_v4().to_string() } else { image_id },
+            id,
+            position,
+            url,
+            details.and_then(|d| d.revised_prompt.as_ref()),
+            details.and_then(|d| d.attempts),
+            details.and_then(|d| d.latency_ms),
+        ])?;
+    }
+
+    let mut stmt = conn.prepare_cached("INSERT INTO history_references (group_id, position, source) VALUES (?1, ?2, ?3)")?;
+    for (position, source) in group.references.iter().enumerate() {
+        stmt.execute(params![id, position, source])?;
+    }
+    Ok(())
+}
+
+/// The `/images/<file>` URLs returned by `sql`; remote URLs have no file.
+fn stored_files(conn: &Connection, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<String>> {
+    let urls = conn.prepare(sql)?.query_map(params, |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(urls.into_iter().filter(|url| url.starts_with("/images/")).collect())
+}
+
+/// Every stored file a group uses.
+fn group_files(conn: &Connection, group_id: i64) -> rusqlite::Result<Vec<String>> {
+    stored_files(
+        conn,
+        "SELECT url FROM history_images WHERE group_id = ?1
+         UNION SELECT source FROM history_references WHERE group_id = ?1
+         UNION SELECT mask FROM history_groups WHERE id = ?1 AND mask IS NOT NULL
+         UNION SELECT source_image FROM history_groups WHERE id = ?1 AND source_image IS NOT NULL",
+        params![group_id],
+    )
+}
+
+/// Whether some row points at `file`, as an image, reference, mask or
+/// variation source.
+fn in_use(conn: &Connection, file: &str) -> rusqlite::Result<bool> {
+    conn.prepare_cached(
+        "SELECT EXISTS (SELECT 1 FROM history_images WHERE url = ?1)
+             OR EXISTS (SELECT 1 FROM history_references WHERE source = ?1)
+             OR EXISTS (SELECT 1 FROM history_groups WHERE mask = ?1 OR source_image = ?1)",
+    )?
+    .query_row(params![file], |row| row.get(0))
+}
+
+/// Drops the files some remaining row still points at, e.g. an image that
+/// another group used as its reference or variation source.
+fn unreferenced(conn: &Connection, mut files: Vec<String>) -> rusqlite::Result<Vec<String>> {
+    files.sort();
+    files.dedup();
+    let mut unused = Vec::new();
+    for file in files {
+        if !in_use(conn, &file)? {
+            unused.push(file);
+        }
+    }
+    Ok(unused)
+}
+
+/// Id of an image in history: the stem of its stored file, so it is also the
+/// bare id [`storage::find_stored`](crate::storage::find_stored) resolves.
+/// Images kept as an upstream URL (saving failed) get a UUID.
+pub fn image_id(url: &str) -> String {
+    match url.strip_prefix("/images/").map(Path::new).and_then(Path::file_stem).and_then(|s| s.to_str()) {
+        Some(stem) if !stem.is_empty() => stem.to_string(),
+        _ => Uuid::new_v4().to_string(),
+    }
+}
+
+/// `LIKE` pattern matching prompts that start with `prefix`.
+fn like_prefix(prefix: &str) -> String {
+    let escaped = prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("{}%", escaped)
+}
+
+fn or_new_id(id: &str) -> String {
+    if id.is_empty() {
+        Uuid::new_v4().to_string()
+    } else {
+        id.to_string()
+    }
+}
+
+const GROUP_COLUMNS: &str = "id, uuid, prompt, timestamp, client_key_id, source_image, negative_prompt, \
+                             model, size, mask, upstream, attempts, latency_ms";
+
+fn read_group(row: &rusqlite::Row) -> rusqlite::Result<(i64, GenerationGroup)> {
+    Ok((
+        row.get(0)?,
+        GenerationGroup {
+            id: row.get(1)?,
+            prompt: row.get(2)?,
+            timestamp: row.get(3)?,
+            images: Vec::new(),
+            client_key_id: row.get(4)?,
+            source_image: row.get(5)?,
+            negative_prompt: row.get(6)?,
+            model: row.get(7)?,
+            size: row.get(8)?,
+            references: Vec::new(),
+            mask: row.get(9)?,
+            upstream: row.get(10)?,
+            attempts: row.get(11)?,
+            latency_ms: row.get(12)?,
+            image_details: Vec::new(),
+        },
+    ))
+}
+
+/// Fills in the images and references of a group read by `read_group`.
+fn with_images(conn: &Connection, id: i64, mut group: GenerationGroup) -> rusqlite::Result<GenerationGroup> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT uuid, url, revised_prompt, attempts, latency_ms FROM history_images WHERE group_id = ?1 ORDER BY position",
+    )?;
+    group.image_details = stmt
+        .query_map(params![id], |row| {
+            Ok(ImageRecord {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                revised_prompt: row.get(2)?,
+                attempts: row.get(3)?,
+                latency_ms: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    group.images = group.image_details.iter().map(|image| image.url.clone()).collect();
+
+    let mut stmt = conn.prepare_cached("SELECT source FROM history_references WHERE group_id = ?1 ORDER BY position")?;
+    group.references = stmt.query_map(params![id], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+    Ok(group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for a legacy file, removed when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("history-test-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrate_creates_the_schema_on_a_new_database() {
+        let dir = TempDir::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn, &dir.0.join(LEGACY_FILE)).unwrap(), 0);
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "history_groups"), 0);
+        assert_eq!(count(&conn, "history_references"), 0);
+        // A second run has nothing to do.
+        assert_eq!(migrate(&mut conn, &dir.0.join(LEGACY_FILE)).unwrap(), 0);
+    }
+
+    #[test]
+    fn migrate_imports_the_legacy_file() {
+        let dir = TempDir::new();
+        let legacy = dir.0.join(LEGACY_FILE);
+        std::fs::write(
+            &legacy,
+            r#"[
+                {"prompt": "newer", "timestamp": 2000, "images": ["/images/b.png", "/images/c.png"]},
+                {"id": "kept-id", "prompt": "older", "timestamp": 1000, "images": ["/images/a.png"], "client_key_id": "key_1"}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn, &legacy).unwrap(), 2);
+        assert_eq!(count(&conn, "history_images"), 3);
+
+        // Oldest first, so row ids follow age; given ids survive, missing ones are filled in.
+        let rows: Vec<(String, String, Option<String>)> = conn
+            .prepare("SELECT uuid, prompt, client_key_id FROM history_groups ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(rows[0], ("kept-id".to_string(), "older".to_string(), Some("key_1".to_string())));
+        assert_eq!(rows[1].1, "newer");
+        assert!(!rows[1].0.is_empty());
+    }
+
+    #[test]
+    fn migrate_sets_aside_a_malformed_legacy_file() {
+        let dir = TempDir::new();
+        let legacy = dir.0.join(LEGACY_FILE);
+        std::fs::write(&legacy, "{not json").unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn, &legacy).unwrap(), 0);
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert!(!legacy.exists());
+        assert_eq!(std::fs::read_to_string(dir.0.join("history.json.bad")).unwrap(), "{not json");
+    }
+
+    #[test]
+    fn migrate_upgrades_a_v1_database() {
+        let dir = TempDir::new();
+        let legacy = dir.0.join(LEGACY_FILE);
+        // Only a brand-new database imports the legacy file.
+        std::fs::write(&legacy, r#"[{"prompt": "ignored", "timestamp": 1, "images": []}]"#).unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE history_groups (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                prompt TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                client_key_id TEXT,
+                source_image TEXT
+            );
+            CREATE INDEX idx_history_groups_timestamp ON history_groups (timestamp);
+            CREATE INDEX idx_history_groups_prompt ON history_groups (prompt);
+            CREATE TABLE history_images (
+                group_id INTEGER NOT NULL REFERENCES history_groups (id) ON DELETE CASCADE,
+                position INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                PRIMARY KEY (group_id, position)
+            );
+            INSERT INTO history_groups (prompt, timestamp) VALUES ('a cat', 1000), ('a dog', 2000);
+            INSERT INTO history_images (group_id, position, url) VALUES (1, 0, '/images/a.png'), (2, 0, '/images/b.png');
+            PRAGMA user_version = 1;",
+        )
+        .unwrap();
+
+        assert_eq!(migrate(&mut conn, &legacy).unwrap(), 0);
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "history_groups"), 2);
+        assert!(legacy.exists());
+
+        // Existing rows got distinct ids, and the new columns and tables exist.
+        let missing: i64 = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM history_groups WHERE uuid IS NULL)
+                      + (SELECT COUNT(*) FROM history_images WHERE uuid IS NULL)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(missing, 0);
+        let distinct: i64 =
+            conn.query_row("SELECT COUNT(DISTINCT uuid) FROM history_groups", [], |row| row.get(0)).unwrap();
+        assert_eq!(distinct, 2);
+        let image_ids: Vec<String> = conn
+            .prepare("SELECT uuid FROM history_images ORDER BY group_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(image_ids, ["a", "b"]);
+        conn.execute("UPDATE history_groups SET model = 'm', attempts = 1 WHERE id = 1", []).unwrap();
+        conn.execute("INSERT INTO history_references (group_id, position, source) VALUES (1, 0, '/images/r.png')", [])
+            .unwrap();
+
+        // The prompt index now serves prefix searches.
+        let plan: String = conn
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT id FROM history_groups WHERE prompt LIKE 'a c%' ESCAPE '\\'",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("idx_history_groups_prompt"), "{}", plan);
+    }
+
+    #[test]
+    fn insert_group_pairs_details_by_position() {
+        let dir = TempDir::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, &dir.0.join(LEGACY_FILE)).unwrap();
+
+        let url = "https://upstream.example.com/same.png".to_string();
+        let detail = |id: &str, revised: &str| ImageRecord {
+            id: id.to_string(),
+            url: url.clone(),
+            revised_prompt: Some(revised.to_string()),
+            attempts: None,
+            latency_ms: None,
+        };
+        let group: GenerationGroup = serde_json::from_value(serde_json::json!({
+            "prompt": "twins",
+            "timestamp": 1,
+            "images": [url, url],
+        }))
+        .unwrap();
+        let group = GenerationGroup { image_details: vec![detail("first", "one"), detail("second", "two")], ..group };
+        insert_group(&conn, &group).unwrap();
+
+        let rows: Vec<(String, String)> = conn
+            .prepare("SELECT uuid, revised_prompt FROM history_images ORDER BY position")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(rows, [("first".to_string(), "one".to_string()), ("second".to_string(), "two".to_string())]);
+    }
+
+    /// Two groups sharing files: the second uses the first's image as a
+    /// reference, and both have the same reference, mask and variation source.
+    fn shared_store() -> HistoryStore {
+        let dir = TempDir::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        migrate(&mut conn, &dir.0.join(LEGACY_FILE)).unwrap();
+        let group = |id: &str, images: &[&str], references: &[&str]| -> GenerationGroup {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "prompt": id,
+                "timestamp": 1,
+                "images": images,
+                "references": references,
+                "mask": "/images/mask.png",
+                "source_image": "/images/source.png",
+            }))
+            .unwrap()
+        };
+        insert_group(&conn, &group("first", &["/images/a.png", "/images/only.png"], &["/images/ref.png"])).unwrap();
+        insert_group(
+            &conn,
+            &group("second", &["/images/b.png"], &["/images/a.png", "/images/ref.png", "https://cdn.example.com/r.png"]),
+        )
+        .unwrap();
+        HistoryStore { conn: Arc::new(Mutex::new(conn)) }
+    }
+
+    fn groups(store: &HistoryStore) -> i64 {
+        count(&store.conn.lock().unwrap(), "history_groups")
+    }
+
+    #[tokio::test]
+    async fn delete_group_keeps_files_another_group_uses() {
+        let store = shared_store();
+        assert!(store.uses("/images/a.png").await.unwrap());
+        assert_eq!(store.delete_group("first").await.unwrap().unwrap(), ["/images/only.png"]);
+        assert!(store.uses("/images/a.png").await.unwrap());
+        assert_eq!(groups(&store), 1);
+
+        // With nothing left to share them, every stored file goes; remote URLs never do.
+        assert_eq!(
+            store.delete_group("second").await.unwrap().unwrap(),
+            ["/images/a.png", "/images/b.png", "/images/mask.png", "/images/ref.png", "/images/source.png"]
+        );
+        assert!(store.delete_group("second").await.unwrap().is_none());
+        assert!(!store.uses("/images/a.png").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn delete_image_keeps_files_another_group_uses() {
+        let store = shared_store();
+        // The second group references this image.
+        assert_eq!(store.delete_image("a").await.unwrap().unwrap(), Vec::<String>::new());
+        assert_eq!(groups(&store), 2);
+
+        // The group's last image takes the group along, but its shared files stay.
+        assert_eq!(store.delete_image("only").await.unwrap().unwrap(), ["/images/only.png"]);
+        assert_eq!(groups(&store), 1);
+        assert!(store.delete_image("only").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn unreferenced_drops_files_still_in_use() {
+        let store = shared_store();
+        let conn = store.conn.lock().unwrap();
+        let files = ["/images/mask.png", "/images/gone.png", "/images/b.png", "/images/gone.png"];
+        assert_eq!(unreferenced(&conn, files.map(str::to_string).to_vec()).unwrap(), ["/images/gone.png"]);
+    }
+
+    #[test]
+    fn image_id_is_the_stored_file_stem() {
+        assert_eq!(image_id("/images/2f1c0b9e.png"), "2f1c0b9e");
+        // Upstream URLs have no local file and get a UUID.
+        assert!(Uuid::parse_str(&image_id("https://cdn.example.com/a.png")).is_ok());
+    }
+
+    #[test]
+    fn like_prefix_escapes_wildcards() {
+        assert_eq!(like_prefix("a cat"), "a cat%");
+        assert_eq!(like_prefix("100%_\\"), "100\\%\\_\\\\%");
+    }
+}
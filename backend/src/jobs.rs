@@ -0,0 +1,481 @@
+//! Asynchronous image generation jobs: `POST /v1/images/jobs` queues a
+//! request and returns immediately, a bounded pool of workers runs it, and
+//! clients poll `GET /v1/images/jobs/{id}`. Job state is persisted to
+//! `jobs.json` so queued and interrupted jobs resume after a restart.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    extract::{Json, Path},
+    keys::ClientIdentity,
+    models::openai,
+    now_secs,
+    progress::{ProgressKind, Reporter},
+    quota::Reservation,
+    AppState,
+};
+
+const JOBS_FILE: &str = "jobs.json";
+
+/// How often expired finished jobs are swept while the queue is idle.
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobError {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub code: String,
+}
+
+impl From<&AppError> for JobError {
+    fn from(e: &AppError) -> Self {
+        JobError { message: e.message().to_string(), error_type: e.error_type().to_string(), code: e.code() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub request: openai::ImageGenerationRequest,
+    pub client_key_id: Option<String>,
+    pub client_name: String,
+    pub reservation: Option<Reservation>,
+    pub result: Option<openai::ImageResponse>,
+    pub error: Option<JobError>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Job {
+    fn identity(&self) -> ClientIdentity {
+        ClientIdentity { key_id: self.client_key_id.clone(), name: self.client_name.clone() }
+    }
+
+    /// Progress for the job, reported under its id.
+    fn progress(&self, state: &AppState) -> Reporter {
+        state.progress.reporter(&self.id, self.client_key_id.clone())
+    }
+
+    /// Admins see every job; client keys only their own.
+    fn visible_to(&self, identity: &ClientIdentity) -> bool {
+        identity.key_id.is_none() || identity.key_id == self.client_key_id
+    }
+}
+
+/// What the API returns for a job; the original request (which may carry
+/// large reference images) stays server-side.
+#[derive(Serialize)]
+pub struct JobView {
+    id: String,
+    object: &'static str,
+    status: JobStatus,
+    model: String,
+    prompt: String,
+    n: usize,
+    created_at: u64,
+    updated_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<openai::ImageResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JobError>,
+}
+
+impl From<&Job> for JobView {
+    fn from(job: &Job) -> Self {
+        JobView {
+            id: job.id.clone(),
+            object: "image.generation.job",
+            status: job.status,
+            model: job.request.model.clone(),
+            prompt: job.request.prompt.clone(),
+            n: job.request.n,
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+            result: job.result.clone(),
+            error: job.error.clone(),
+        }
+    }
+}
+
+pub struct JobQueue {
+    jobs: RwLock<HashMap<String, Job>>,
+    sender: mpsc::Sender<String>,
+    running: Mutex<HashMap<String, AbortHandle>>,
+    /// Serializes writes of `jobs.json` so the last one holds the newest state.
+    saving: Mutex<()>,
+    /// Seconds finished jobs are kept for polling.
+    retention: u64,
+}
+
+/// Drops finished jobs last updated more than `retention` seconds ago.
+fn prune(jobs: &mut HashMap<String, Job>, retention: u64) {
+    let cutoff = now_secs().saturating_sub(retention);
+    jobs.retain(|_, job| !job.status.is_finished() || job.updated_at >= cutoff);
+}
+
+impl JobQueue {
+    /// Loads persisted jobs, dropping finished ones older than `retention`
+    /// seconds. Returns the queue and the receiver for [`spawn_workers`].
+    pub async fn load(capacity: usize, retention: u64) -> (Self, mpsc::Receiver<String>) {
+        let mut jobs: HashMap<String, Job> = match tokio::fs::read_to_string(JOBS_FILE).await {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    // Keep the file for a manual look, but don't refuse to start.
+                    let bad = format!("{}.bad", JOBS_FILE);
+                    tracing::error!("{} 无法解析，已重命名为 {}，任务队列从空开始: {}", JOBS_FILE, bad, e);
+                    if let Err(e) = tokio::fs::rename(JOBS_FILE, &bad).await {
+                        tracing::warn!("重命名 {} 失败: {}", JOBS_FILE, e);
+                    }
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        prune(&mut jobs, retention);
+
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let queue = JobQueue {
+            jobs: RwLock::new(jobs),
+            sender,
+            running: Mutex::new(HashMap::new()),
+            saving: Mutex::new(()),
+            retention,
+        };
+        (queue, receiver)
+    }
+
+    /// Drops expired finished jobs, then writes a temporary file and renames
+    /// it over `jobs.json`, so a crash mid-write never leaves a truncated file.
+    async fn save(&self) {
+        let _saving = self.saving.lock().await;
+        let snapshot = {
+            let mut jobs = self.jobs.write().await;
+            prune(&mut jobs, self.retention);
+            serde_json::to_string_pretty(&*jobs).unwrap()
+        };
+        let temp = format!("{}.tmp", JOBS_FILE);
+        let written = match tokio::fs::write(&temp, snapshot).await {
+            Ok(()) => tokio::fs::rename(&temp, JOBS_FILE).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            tracing::error!("保存任务队列失败: {}", e);
+        }
+    }
+
+    async fn update(&self, id: &str, apply: impl FnOnce(&mut Job)) -> Option<Job> {
+        let updated = {
+            let mut jobs = self.jobs.write().await;
+            let job = jobs.get_mut(id)?;
+            apply(job);
+            job.updated_at = now_secs();
+            job.clone()
+        };
+        self.save().await;
+        Some(updated)
+    }
+
+    /// Records the outcome of a running job. A job cancelled meanwhile stays
+    /// cancelled, even if it got to finish.
+    async fn finish(&self, id: &str, apply: impl FnOnce(&mut Job)) {
+        self.update(id, |job| {
+            if job.status == JobStatus::Running {
+                apply(job);
+            }
+        })
+        .await;
+    }
+}
+
+/// Starts `workers` tasks pulling job ids off the queue, re-queues jobs that
+/// were queued or running when the server last stopped, and periodically
+/// drops finished jobs past their retention.
+pub fn spawn_workers(state: AppState, receiver: mpsc::Receiver<String>, workers: usize) {
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..workers.max(1) {
+        let state = state.clone();
+        let receiver = receiver.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(id) = receiver.lock().await.recv().await else { break };
+                run_job(&state, &id).await;
+            }
+        });
+    }
+
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let expired = {
+                    let mut jobs = state.jobs.jobs.write().await;
+                    let before = jobs.len();
+                    prune(&mut jobs, state.jobs.retention);
+                    before - jobs.len()
+                };
+                if expired > 0 {
+                    tracing::debug!("已清理 {} 个过期任务", expired);
+                    state.jobs.save().await;
+                }
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        let pending: Vec<String> = {
+            let jobs = state.jobs.jobs.read().await;
+            let mut pending: Vec<&Job> = jobs.values().filter(|j| !j.status.is_finished()).collect();
+            pending.sort_by_key(|j| j.created_at);
+            pending.into_iter().map(|j| j.id.clone()).collect()
+        };
+        if !pending.is_empty() {
+            tracing::info!("恢复 {} 个未完成的生成任务", pending.len());
+        }
+        for id in pending {
+            if let Some(job) = state.jobs.update(&id, |job| job.status = JobStatus::Queued).await {
+                job.progress(&state).emit(None, ProgressKind::Queued);
+            }
+            if state.jobs.sender.send(id).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+async fn run_job(state: &AppState, id: &str) {
+    // Starting the task and registering its abort handle happen under the
+    // same lock as the status change, so `cancel_job` always finds the handle
+    // of a running job.
+    let (job, task) = {
+        let mut jobs = state.jobs.jobs.write().await;
+        // Gone, or cancelled while it was waiting in the queue.
+        let Some(job) = jobs.get_mut(id).filter(|job| job.status == JobStatus::Queued) else {
+            return;
+        };
+        job.status = JobStatus::Running;
+        job.updated_at = now_secs();
+        let job = job.clone();
+        let task = {
+            let state = state.clone();
+            let job = job.clone();
+            // The reservation is settled below, once, whatever the outcome.
+            tokio::spawn(async move {
+                let progress = job.progress(&state);
+                crate::complete_generation(&state, &job.identity(), &job.request, None, &progress, None).await
+            })
+        };
+        state.jobs.running.lock().await.insert(id.to_string(), task.abort_handle());
+        (job, task)
+    };
+    state.jobs.save().await;
+
+    tracing::info!("▶️ 开始执行任务 {} [{}]: {}", job.id, job.client_name, job.request.prompt);
+
+    let outcome = task.await;
+    state.jobs.running.lock().await.remove(id);
+
+    if let Some(reservation) = &job.reservation {
+        let produced = match &outcome {
+            Ok(Ok(data)) => data.data.len() as u64,
+            _ => 0,
+        };
+        state.quota.settle(reservation, produced).await;
+    }
+
+    match outcome {
+        Ok(Ok(data)) => {
+            tracing::info!("✅ 任务 {} 完成，共 {} 张图像", id, data.data.len());
+            state.jobs.finish(id, |job| {
+                job.status = JobStatus::Succeeded;
+                job.result = Some(data);
+            })
+            .await;
+        }
+        Ok(Err(e)) => {
+            tracing::warn!("❌ 任务 {} 失败: {}", id, e);
+            state.jobs.finish(id, |job| {
+                job.status = JobStatus::Failed;
+                job.error = Some(JobError::from(&e));
+            })
+            .await;
+        }
+        // Aborted by `cancel_job`, which already set the status.
+        Err(join_error) if join_error.is_cancelled() => {}
+        Err(join_error) => {
+            tracing::error!("任务 {} 异常退出: {}", id, join_error);
+            state.jobs.finish(id, |job| {
+                job.status = JobStatus::Failed;
+                job.error = Some(JobError::from(&AppError::Storage("Job worker crashed".to_string())));
+            })
+            .await;
+        }
+    }
+}
+
+pub async fn create_job(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    Json(mut payload): Json<openai::ImageGenerationRequest>,
+) -> Result<(StatusCode, Json<JobView>), AppError> {
+    let reservation = crate::admit_generation(&state, &identity, &payload).await?;
+
+    let now = now_secs();
+    let id = format!("job_{}", Uuid::new_v4().simple());
+    // Progress for a job is always reported under its job id.
+    payload.task_id = Some(id.clone());
+    let job = Job {
+        id,
+        status: JobStatus::Queued,
+        request: payload,
+        client_key_id: identity.key_id.clone(),
+        client_name: identity.name.clone(),
+        reservation,
+        result: None,
+        error: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let permit = match state.jobs.sender.try_reserve() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if let Some(reservation) = &job.reservation {
+                state.quota.settle(reservation, 0).await;
+            }
+            return Err(AppError::RateLimited { message: "Job queue is full".to_string(), retry_after: 10 });
+        }
+    };
+
+    let view = JobView::from(&job);
+    state.jobs.jobs.write().await.insert(job.id.clone(), job.clone());
+    state.jobs.save().await;
+    job.progress(&state).emit(None, ProgressKind::Queued);
+    permit.send(job.id.clone());
+    tracing::info!("📥 任务 {} 已入队 [{}]: {}", job.id, identity.name, job.request.prompt);
+
+    Ok((StatusCode::ACCEPTED, Json(view)))
+}
+
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    Path(id): Path<String>,
+) -> Result<Json<JobView>, AppError> {
+    let jobs = state.jobs.jobs.read().await;
+    let job = jobs
+        .get(&id)
+        .filter(|job| job.visible_to(&identity))
+        .ok_or_else(|| AppError::NotFound(format!("Job {} not found", id)))?;
+    Ok(Json(JobView::from(job)))
+}
+
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    Path(id): Path<String>,
+) -> Result<Json<JobView>, AppError> {
+    let (job, was_running) = {
+        let mut jobs = state.jobs.jobs.write().await;
+        let job = jobs
+            .get_mut(&id)
+            .filter(|job| job.visible_to(&identity))
+            .ok_or_else(|| AppError::NotFound(format!("Job {} not found", id)))?;
+        if job.status.is_finished() {
+            return Err(AppError::BadRequest(format!("Job {} has already finished", id)));
+        }
+        let was_running = job.status == JobStatus::Running;
+        job.status = JobStatus::Cancelled;
+        job.updated_at = now_secs();
+        if was_running {
+            if let Some(handle) = state.jobs.running.lock().await.get(&id) {
+                handle.abort();
+            }
+        }
+        (job.clone(), was_running)
+    };
+    state.jobs.save().await;
+
+    // A running job's worker settles the reservation; one that never started
+    // spent nothing, and the worker will skip it.
+    if !was_running {
+        if let Some(reservation) = &job.reservation {
+            state.quota.settle(reservation, 0).await;
+        }
+    }
+    job.progress(&state).emit(None, ProgressKind::Cancelled);
+    tracing::info!("🛑 任务 {} 已取消", id);
+
+    Ok(Json(JobView::from(&job)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: &str, status: JobStatus, updated_at: u64) -> Job {
+        Job {
+            id: id.to_string(),
+            status,
+            request: serde_json::from_value(serde_json::json!({ "prompt": "a cat" })).unwrap(),
+            client_key_id: None,
+            client_name: "admin".to_string(),
+            reservation: None,
+            result: None,
+            error: None,
+            created_at: updated_at,
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn prune_drops_only_expired_finished_jobs() {
+        let now = now_secs();
+        let mut jobs: HashMap<String, Job> = [
+            job("old_done", JobStatus::Succeeded, now - 7200),
+            job("old_cancelled", JobStatus::Cancelled, now - 7200),
+            job("old_queued", JobStatus::Queued, now - 7200),
+            job("old_running", JobStatus::Running, now - 7200),
+            job("new_failed", JobStatus::Failed, now - 10),
+        ]
+        .into_iter()
+        .map(|job| (job.id.clone(), job))
+        .collect();
+
+        prune(&mut jobs, 3600);
+        let mut kept: Vec<&str> = jobs.keys().map(String::as_str).collect();
+        kept.sort();
+        assert_eq!(kept, ["new_failed", "old_queued", "old_running"]);
+    }
+}
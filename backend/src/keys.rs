@@ -0,0 +1,151 @@
+//! Client API keys issued by the gateway for the `/v1/*` routes. Only a
+//! SHA-256 digest of each key is persisted, in `api_keys.json` next to
+//! `config.json`; the plaintext is shown once, when the key is created.
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    auth,
+    error::AppError,
+    extract::{Json, Path},
+    AppState,
+};
+
+const KEYS_FILE: &str = "api_keys.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientKey {
+    pub id: String,
+    pub name: String,
+    /// First characters of the key, so admins can tell keys apart.
+    pub prefix: String,
+    pub key_hash: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+/// Who is calling a `/v1/*` route; inserted into request extensions by
+/// [`require_client_key`].
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    /// `None` when the caller authenticated with the admin token.
+    pub key_id: Option<String>,
+    pub name: String,
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub async fn load_keys() -> Vec<ClientKey> {
+    match tokio::fs::read_to_string(KEYS_FILE).await {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+async fn save_keys(keys: &[ClientKey]) -> Result<(), AppError> {
+    tokio::fs::write(KEYS_FILE, serde_json::to_string_pretty(keys).unwrap())
+        .await
+        .map_err(|e| AppError::Config(format!("Failed to write {}: {}", KEYS_FILE, e)))
+}
+
+/// Validates `Authorization: Bearer` against the issued keys. The admin token
+/// is accepted too, so the bundled web UI keeps working.
+pub async fn require_client_key(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let token = auth::bearer_token(request.headers())
+        .ok_or_else(|| AppError::Unauthorized("Missing bearer API key".to_string()))?
+        .to_string();
+
+    let identity = if auth::is_admin(&state, request.headers()).await {
+        ClientIdentity { key_id: None, name: "admin".to_string() }
+    } else {
+        let digest = hash_key(&token);
+        let keys = state.keys.read().await;
+        let key = keys
+            .iter()
+            .find(|k| !k.revoked && auth::constant_time_eq(&k.key_hash, &digest))
+            .ok_or_else(|| AppError::Unauthorized("Invalid API key".to_string()))?;
+        ClientIdentity { key_id: Some(key.id.clone()), name: key.name.clone() }
+    };
+
+    request.extensions_mut().insert(identity);
+    Ok(next.run(request).await)
+}
+
+pub async fn list_keys(State(state): State<AppState>) -> impl IntoResponse {
+    let keys = state.keys.read().await;
+    Json(keys.clone())
+}
+
+#[derive(Deserialize)]
+pub struct CreateKeyRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct CreatedKey {
+    #[serde(flatten)]
+    record: ClientKey,
+    /// Plaintext key; not retrievable after this response.
+    key: String,
+}
+
+pub async fn create_key(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedKey>), AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("Key name must not be empty".to_string()));
+    }
+
+    let key = format!("gk-{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let record = ClientKey {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        prefix: key[..10].to_string(),
+        key_hash: hash_key(&key),
+        created_at: crate::now_secs(),
+        revoked: false,
+    };
+
+    let mut keys = state.keys.write().await;
+    keys.push(record.clone());
+    save_keys(&keys).await?;
+    tracing::info!("已创建客户端密钥: {} ({})", record.name, record.id);
+
+    Ok((StatusCode::CREATED, Json(CreatedKey { record, key })))
+}
+
+/// Revokes rather than deletes, so history stays attributable to the key.
+pub async fn revoke_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let mut keys = state.keys.write().await;
+    let key = keys
+        .iter_mut()
+        .find(|k| k.id == id)
+        .ok_or_else(|| AppError::NotFound(format!("API key {} not found", id)))?;
+    key.revoked = true;
+    tracing::info!("已吊销客户端密钥: {} ({})", key.name, key.id);
+    save_keys(&keys).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,11 +1,13 @@
 use axum::{
-    extract::{Json, State, Query},
+    extract::{DefaultBodyLimit, Extension, State},
     http::StatusCode,
     response::{IntoResponse, Response},
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
@@ -14,24 +16,41 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use std::io::Write;
 use zip::write::SimpleFileOptions;
 use std::path::Path;
+use futures::StreamExt;
+use base64::Engine;
 
+mod auth;
+mod chat;
+mod edits;
+mod error;
+mod extract;
+mod history;
+mod jobs;
+mod keys;
 mod models;
+mod progress;
+mod quota;
+mod references;
+mod retry;
+mod size;
 mod storage;
+mod upstream;
+
+use error::AppError;
+use extract::{Json, Query};
 
 #[derive(Clone)]
 struct AppState {
     config: Arc<RwLock<Config>>,
-    history: Arc<RwLock<Vec<GenerationGroup>>>,
+    history: history::HistoryStore,
+    keys: Arc<RwLock<Vec<keys::ClientKey>>>,
+    quota: Arc<quota::QuotaTracker>,
+    upstreams: Arc<upstream::UpstreamPool>,
+    jobs: Arc<jobs::JobQueue>,
+    progress: Arc<progress::ProgressHub>,
     client: reqwest::Client,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct GenerationGroup {
-    pub prompt: String,
-    pub timestamp: u64,
-    pub images: Vec<String>,
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Config {
     gemini_proxy_url: String,
@@ -44,10 +63,131 @@ struct Config {
     pub timeout: u64,
     #[serde(default = "default_retries")]
     pub retry_limit: usize,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    /// Upper bound in seconds on one generation request, across all retries
+    /// and the fallback proxy.
+    #[serde(default = "default_request_deadline")]
+    pub request_deadline: u64,
+    #[serde(default = "default_generation_concurrency")]
+    pub generation_concurrency: usize,
+    #[serde(default = "default_max_b64_bytes")]
+    pub max_b64_bytes: u64,
+    /// Largest reference image accepted, uploaded or inline.
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: u64,
+    /// Largest total of reference images in one request; bounds the memory a
+    /// single request can take. Applied at startup.
+    #[serde(default = "default_max_request_bytes")]
+    pub max_request_bytes: u64,
+    /// Reference images are downscaled so their longest side is at most
+    /// this many pixels before going upstream; 0 keeps the original size.
+    #[serde(default = "default_reference_max_dimension")]
+    pub reference_max_dimension: u32,
+    /// Format reference images are re-encoded to: `jpeg`, `webp` or `png`.
+    #[serde(default)]
+    pub reference_format: references::ReferenceFormat,
+    /// JPEG/WebP quality (1-100) for re-encoded reference images.
+    #[serde(default = "default_reference_quality")]
+    pub reference_quality: u8,
+    /// Limits for client API keys without an entry in `key_limits`.
+    #[serde(default)]
+    pub client_limits: quota::ClientLimits,
+    /// Per-key overrides, keyed by client key id.
+    #[serde(default)]
+    pub key_limits: HashMap<String, quota::ClientLimits>,
+    /// Upstream pool; when empty, `gemini_proxy_url` and `fallback_proxy_url`
+    /// are used as a primary/fallback pair.
+    #[serde(default)]
+    pub upstreams: Vec<upstream::UpstreamConfig>,
+    /// Seconds between upstream health probes; 0 disables probing.
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval: u64,
+    /// Consecutive failures before an upstream's circuit breaker opens; 0 disables it.
+    #[serde(default = "default_breaker_failure_threshold")]
+    pub breaker_failure_threshold: u32,
+    /// Seconds an open breaker waits before letting a trial request through.
+    #[serde(default = "default_breaker_cooldown")]
+    pub breaker_cooldown: u64,
+    /// Workers running queued `/v1/images/jobs` requests.
+    #[serde(default = "default_job_workers")]
+    pub job_workers: usize,
+    /// Jobs that may wait for a worker before new ones are rejected.
+    #[serde(default = "default_job_queue_capacity")]
+    pub job_queue_capacity: usize,
+    /// Seconds finished jobs are kept for polling.
+    #[serde(default = "default_job_retention")]
+    pub job_retention: u64,
 }
 
 fn default_timeout() -> u64 { 300 }
 fn default_retries() -> usize { 10 }
+fn default_retry_base_delay_ms() -> u64 { 1000 }
+fn default_retry_max_delay_ms() -> u64 { 30_000 }
+fn default_request_deadline() -> u64 { 900 }
+fn default_generation_concurrency() -> usize { 4 }
+fn default_max_b64_bytes() -> u64 { 20 * 1024 * 1024 }
+fn default_max_upload_bytes() -> u64 { 20 * 1024 * 1024 }
+fn default_max_request_bytes() -> u64 { 50 * 1024 * 1024 }
+fn default_reference_max_dimension() -> u32 { 2048 }
+fn default_reference_quality() -> u8 { 90 }
+fn default_health_check_interval() -> u64 { 30 }
+fn default_breaker_failure_threshold() -> u32 { 5 }
+fn default_breaker_cooldown() -> u64 { 30 }
+fn default_job_workers() -> usize { 2 }
+fn default_job_queue_capacity() -> usize { 100 }
+fn default_job_retention() -> u64 { 86_400 }
+
+/// Upper bound for `n`, matching the OpenAI images API.
+const MAX_IMAGES_PER_REQUEST: usize = 10;
+
+const MASK_INSTRUCTIONS: &str = "The next image is an edit mask for the first reference image. \
+Only change the regions where the mask is transparent; keep every other part of the first image exactly as it is.";
+
+/// Per-request snapshot of the config values `perform_generation` needs.
+#[derive(Clone, Debug)]
+struct GenerationSettings {
+    storage_path: String,
+    timeout: u64,
+    retry: retry::RetryPolicy,
+    breaker: upstream::BreakerSettings,
+    concurrency: usize,
+    max_b64_bytes: u64,
+    references: references::ReferenceSettings,
+}
+
+impl Config {
+    fn limits_for(&self, key_id: &str) -> quota::ClientLimits {
+        self.key_limits.get(key_id).unwrap_or(&self.client_limits).clone()
+    }
+
+    fn generation_settings(&self) -> GenerationSettings {
+        GenerationSettings {
+            storage_path: self.storage_path.clone(),
+            timeout: self.timeout,
+            retry: retry::RetryPolicy {
+                retry_limit: self.retry_limit,
+                base_delay: std::time::Duration::from_millis(self.retry_base_delay_ms),
+                max_delay: std::time::Duration::from_millis(self.retry_max_delay_ms.max(self.retry_base_delay_ms)),
+                deadline: tokio::time::Instant::now() + std::time::Duration::from_secs(self.request_deadline),
+            },
+            breaker: upstream::BreakerSettings {
+                failure_threshold: self.breaker_failure_threshold,
+                cooldown: std::time::Duration::from_secs(self.breaker_cooldown),
+            },
+            concurrency: self.generation_concurrency.max(1),
+            max_b64_bytes: self.max_b64_bytes,
+            references: references::ReferenceSettings {
+                max_dimension: self.reference_max_dimension,
+                format: self.reference_format,
+                quality: self.reference_quality,
+                max_bytes: self.max_upload_bytes,
+            },
+        }
+    }
+}
 
 impl Default for Config {
     fn default() -> Self {
@@ -60,10 +200,34 @@ impl Default for Config {
             port: 3000,
             timeout: 300,
             retry_limit: 10,
+            retry_base_delay_ms: default_retry_base_delay_ms(),
+            retry_max_delay_ms: default_retry_max_delay_ms(),
+            request_deadline: default_request_deadline(),
+            generation_concurrency: 4,
+            max_b64_bytes: default_max_b64_bytes(),
+            max_upload_bytes: default_max_upload_bytes(),
+            max_request_bytes: default_max_request_bytes(),
+            reference_max_dimension: default_reference_max_dimension(),
+            reference_format: references::ReferenceFormat::default(),
+            reference_quality: default_reference_quality(),
+            client_limits: quota::ClientLimits::default(),
+            key_limits: HashMap::new(),
+            upstreams: Vec::new(),
+            health_check_interval: default_health_check_interval(),
+            breaker_failure_threshold: default_breaker_failure_threshold(),
+            breaker_cooldown: default_breaker_cooldown(),
+            job_workers: default_job_workers(),
+            job_queue_capacity: default_job_queue_capacity(),
+            job_retention: default_job_retention(),
         }
     }
 }
 
+/// Seconds since the Unix epoch.
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
 async fn load_config() -> Config {
     match tokio::fs::read_to_string("config.json").await {
         Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
@@ -75,19 +239,10 @@ async fn load_config() -> Config {
     }
 }
 
-async fn save_config(config: &Config) -> std::io::Result<()> {
-    tokio::fs::write("config.json", serde_json::to_string_pretty(config).unwrap()).await
-}
-
-async fn load_history() -> Vec<GenerationGroup> {
-    match tokio::fs::read_to_string("history.json").await {
-        Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
-        Err(_) => Vec::new(),
-    }
-}
-
-async fn save_history(history: &[GenerationGroup]) -> std::io::Result<()> {
-    tokio::fs::write("history.json", serde_json::to_string_pretty(history).unwrap()).await
+async fn save_config(config: &Config) -> Result<(), AppError> {
+    tokio::fs::write("config.json", serde_json::to_string_pretty(config).unwrap())
+        .await
+        .map_err(|e| AppError::Config(format!("Failed to write config.json: {}", e)))
 }
 
 #[tokio::main]
@@ -98,24 +253,60 @@ async fn main() {
         .init();
 
     let config = load_config().await;
-    let history = load_history().await;
+    let history = history::HistoryStore::open().unwrap_or_else(|e| panic!("打开历史记录数据库失败: {}", e));
+    let client_keys = keys::load_keys().await;
     let port = config.port;
     let storage_path = config.storage_path.clone();
-    
+    let job_workers = config.job_workers;
+    let upload_limit = edits::body_limit(config.max_request_bytes);
+    let json_limit = references::json_body_limit(config.max_request_bytes);
+    let (job_queue, job_receiver) = jobs::JobQueue::load(config.job_queue_capacity, config.job_retention).await;
+
     let state = AppState {
         config: Arc::new(RwLock::new(config)),
-        history: Arc::new(RwLock::new(history)),
+        history,
+        keys: Arc::new(RwLock::new(client_keys)),
+        quota: Arc::new(quota::QuotaTracker::load().await),
+        upstreams: Arc::new(upstream::UpstreamPool::default()),
+        jobs: Arc::new(job_queue),
+        progress: Arc::new(progress::ProgressHub::default()),
         client: reqwest::Client::new(),
     };
 
     let _ = tokio::fs::create_dir_all(&storage_path).await;
+    upstream::spawn_health_checks(state.clone());
+    jobs::spawn_workers(state.clone(), job_receiver, job_workers);
 
-    let app = Router::new()
-        .route("/v1/images/generations", post(generate_image))
+    let admin_routes = Router::new()
         .route("/api/config", get(get_config).post(update_config))
-        .route("/api/history", get(get_history).delete(clear_history))
+        .route("/api/history", delete(clear_history))
+        .route("/api/history/:id", delete(delete_history_group))
+        .route("/api/images/:id", delete(delete_history_image))
+        .route("/api/keys", get(keys::list_keys).post(keys::create_key))
+        .route("/api/keys/:id", delete(keys::revoke_key))
+        .route("/api/upstreams", get(upstream::list_upstreams))
+        .route("/api/upstreams/:name/reset", post(upstream::reset_upstream_breaker))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_admin));
+
+    let client_routes = Router::new()
+        .route("/v1/chat/completions", post(chat::openai_chat_completions))
+        .route("/v1/models", get(upstream::list_models))
+        // Bodies are capped by `max_request_bytes`; each reference is also
+        // checked against `max_upload_bytes`.
+        .route("/v1/images/generations", post(generate_image).layer(DefaultBodyLimit::max(json_limit)))
+        .route("/v1/images/edits", post(edits::edit_image).layer(DefaultBodyLimit::max(upload_limit)))
+        .route("/v1/images/variations", post(edits::create_variation).layer(DefaultBodyLimit::max(upload_limit)))
+        .route("/v1/images/events", get(progress::stream_events))
+        .route("/v1/images/jobs", post(jobs::create_job).layer(DefaultBodyLimit::max(json_limit)))
+        .route("/v1/images/jobs/:id", get(jobs::get_job).delete(jobs::cancel_job))
+        .route_layer(middleware::from_fn_with_state(state.clone(), keys::require_client_key));
+
+    let app = Router::new()
+        .route("/api/history", get(get_history))
+        .merge(admin_routes)
+        .merge(client_routes)
         .route("/api/enhance-prompt", post(enhance_prompt))
-        .route("/api/chat", post(chat_completions))
+        .route("/api/chat", post(chat::chat_completions))
         .route("/api/export-zip", get(export_zip))
         .route("/health", get(|| async { "OK" }))
         .nest_service("/images", ServeDir::new(&storage_path))
@@ -130,87 +321,126 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-#[derive(Deserialize)]
-struct ChatRequest {
-    messages: Vec<models::openai::ChatMessage>,
-    model: Option<String>,
+/// Best upstream for a single non-retried call (chat, prompt enhancement).
+async fn preferred_upstream(state: &AppState, model: &str) -> Result<upstream::UpstreamTarget, AppError> {
+    let config = state.config.read().await;
+    state
+        .upstreams
+        .plan(&config, model)
+        .await
+        .into_iter()
+        .next()
+        .ok_or_else(|| AppError::Config(format!("No upstream is configured for model {}", model)))
 }
 
-async fn chat_completions(
+async fn get_config(State(state): State<AppState>) -> impl IntoResponse {
+    let mut config = state.config.read().await.clone();
+    config.api_key = auth::REDACTED.to_string();
+    config.admin_token = auth::REDACTED.to_string();
+    for upstream in &mut config.upstreams {
+        if upstream.api_key.is_some() {
+            upstream.api_key = Some(auth::REDACTED.to_string());
+        }
+    }
+    Json(config)
+}
+
+async fn update_config(
     State(state): State<AppState>,
-    Json(payload): Json<ChatRequest>,
-) -> impl IntoResponse {
-    let (url_str, api_key) = {
-        let config = state.config.read().await;
-        (config.gemini_proxy_url.clone(), config.api_key.clone())
-    };
+    Json(mut new_config): Json<Config>,
+) -> Result<StatusCode, AppError> {
+    let mut config = state.config.write().await;
+    // Secrets come back redacted from `get_config`; keep the stored values.
+    if new_config.api_key == auth::REDACTED {
+        new_config.api_key = config.api_key.clone();
+    }
+    if new_config.admin_token == auth::REDACTED || new_config.admin_token.is_empty() {
+        new_config.admin_token = config.admin_token.clone();
+    }
+    for upstream in &mut new_config.upstreams {
+        if upstream.api_key.as_deref() == Some(auth::REDACTED) {
+            upstream.api_key = config
+                .upstreams
+                .iter()
+                .find(|stored| stored.name == upstream.name)
+                .and_then(|stored| stored.api_key.clone());
+        }
+    }
+    *config = new_config;
+    save_config(&config).await?;
+    Ok(StatusCode::OK)
+}
 
-    let url = if url_str.contains("/v1") {
-        format!("{}/chat/completions", url_str.trim_end_matches('/'))
-    } else {
-        format!("{}/v1/chat/completions", url_str.trim_end_matches('/'))
-    };
+async fn get_history(
+    State(state): State<AppState>,
+    Query(query): Query<history::HistoryQuery>,
+) -> Result<Json<Vec<history::GenerationGroup>>, AppError> {
+    Ok(Json(state.history.list(query).await?))
+}
 
-    let model = payload.model.unwrap_or_else(|| "gemini-3-flash".to_string());
+#[derive(Deserialize)]
+struct ClearHistoryQuery {
+    /// Also delete the image files, not just the records.
+    #[serde(default)]
+    purge_files: bool,
+}
 
-    let chat_payload = serde_json::json!({
-        "model": model,
-        "messages": payload.messages,
-    });
+async fn clear_history(
+    State(state): State<AppState>,
+    Query(query): Query<ClearHistoryQuery>,
+) -> Result<StatusCode, AppError> {
+    let urls = state.history.clear().await?;
+    if query.purge_files {
+        remove_image_files(&state, &urls).await;
+    }
+    Ok(StatusCode::OK)
+}
 
-    let response = state.client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&chat_payload)
-        .send()
-        .await;
-
-    match response {
-        Ok(resp) => {
-            let status = resp.status();
-            if status.is_success() {
-                match resp.json::<serde_json::Value>().await {
-                    Ok(data) => return (StatusCode::OK, Json(data)).into_response(),
-                    Err(e) => tracing::error!("解析聊天响应失败: {}", e),
-                }
-            } else {
-                let error_text = resp.text().await.unwrap_or_default();
-                tracing::error!("聊天请求失败 ({}): {}", status, error_text);
+/// `DELETE /api/history/:id` removes a group, and the files (images,
+/// references, mask, variation source) no other group uses. A numeric
+/// id is also tried as a timestamp, for clients from before groups had ids.
+async fn delete_history_group(
+    State(state): State<AppState>,
+    extract::Path(id): extract::Path<String>,
+) -> Result<StatusCode, AppError> {
+    let mut deleted = state.history.delete_group(&id).await?;
+    if deleted.is_none() {
+        if let Ok(timestamp) = id.parse::<u64>() {
+            if let Some(group) = state.history.find_by_timestamp(timestamp).await? {
+                deleted = state.history.delete_group(&group.id).await?;
             }
-            (StatusCode::BAD_GATEWAY, "Failed to call chat completion").into_response()
-        }
-        Err(e) => {
-            tracing::error!("连接代理失败: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
         }
     }
+    let urls = deleted.ok_or_else(|| AppError::NotFound("History group not found".to_string()))?;
+    remove_image_files(&state, &urls).await;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_config(State(state): State<AppState>) -> impl IntoResponse {
-    let config = state.config.read().await;
-    Json(config.clone())
-}
-
-async fn update_config(
+/// `DELETE /api/images/:id` removes one image from its group, and its file
+/// unless another group uses it.
+async fn delete_history_image(
     State(state): State<AppState>,
-    Json(new_config): Json<Config>,
-) -> impl IntoResponse {
-    let mut config = state.config.write().await;
-    *config = new_config.clone();
-    let _ = save_config(&config).await;
-    StatusCode::OK
-}
-
-async fn get_history(State(state): State<AppState>) -> impl IntoResponse {
-    let history = state.history.read().await;
-    Json(history.clone())
+    extract::Path(id): extract::Path<String>,
+) -> Result<StatusCode, AppError> {
+    let files = state
+        .history
+        .delete_image(&id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Image not found".to_string()))?;
+    remove_image_files(&state, &files).await;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-async fn clear_history(State(state): State<AppState>) -> impl IntoResponse {
-    let mut history = state.history.write().await;
-    history.clear();
-    let _ = save_history(&history).await;
-    StatusCode::OK
+/// The records are already gone by now, so a file that cannot be deleted is
+/// logged rather than failing the request.
+async fn remove_image_files(state: &AppState, urls: &[String]) {
+    let storage_path = state.config.read().await.storage_path.clone();
+    for url in urls {
+        if let Err(e) = storage::remove_stored(&storage_path, url).await {
+            tracing::warn!("删除图片文件失败: {}", e);
+        }
+    }
+    tracing::info!("已删除 {} 个不再使用的图片文件", urls.len());
 }
 
 #[derive(Deserialize)]
@@ -221,17 +451,8 @@ struct EnhanceRequest {
 async fn enhance_prompt(
     State(state): State<AppState>,
     Json(payload): Json<EnhanceRequest>,
-) -> impl IntoResponse {
-    let (url_str, api_key) = {
-        let config = state.config.read().await;
-        (config.gemini_proxy_url.clone(), config.api_key.clone())
-    };
-
-    let url = if url_str.contains("/v1") {
-        format!("{}/chat/completions", url_str.trim_end_matches('/'))
-    } else {
-        format!("{}/v1/chat/completions", url_str.trim_end_matches('/'))
-    };
+) -> Result<String, AppError> {
+    let target = preferred_upstream(&state, "gemini-3-flash").await?;
 
     let chat_payload = serde_json::json!({
         "model": "gemini-3-flash", // Use gemini-3-flash for enhancement as requested
@@ -252,174 +473,354 @@ async fn enhance_prompt(
         ]
     });
 
-    let response = state.client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", api_key))
+    let resp = state.client
+        .post(&target.url)
+        .header("Authorization", format!("Bearer {}", target.api_key))
         .json(&chat_payload)
         .send()
-        .await;
-
-    match response {
-        Ok(resp) => {
-            let status = resp.status();
-            if status.is_success() {
-                match resp.json::<models::openai::ChatCompletionResponse>().await {
-                    Ok(data) => {
-                        if let Some(choice) = data.choices.first() {
-                            let content = choice.message.content.trim().to_string();
-                            tracing::info!("提示词美化成功: {} -> {}", payload.prompt, content);
-                            return (StatusCode::OK, content).into_response();
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!("解析美化响应失败: {}", e);
-                    }
-                }
-            } else {
-                let error_text = resp.text().await.unwrap_or_default();
-                tracing::error!("美化请求失败 ({}): {}", status, error_text);
-            }
-            (StatusCode::BAD_GATEWAY, "Failed to enhance prompt").into_response()
-        }
-        Err(e) => {
-            tracing::error!("连接代理失败: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-        }
+        .await
+        .inspect_err(|e| tracing::error!("连接代理失败: {}", e))?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let error_text = resp.text().await.unwrap_or_default();
+        tracing::error!("美化请求失败 ({}): {}", status, error_text);
+        return Err(AppError::from_upstream_status(status, &error_text));
     }
+
+    let data = resp
+        .json::<models::openai::ChatCompletionResponse>()
+        .await
+        .inspect_err(|e| tracing::error!("解析美化响应失败: {}", e))?;
+    let choice = data
+        .choices
+        .first()
+        .ok_or_else(|| AppError::Parse("Upstream returned no choices".to_string()))?;
+    let content = choice.message.content.trim().to_string();
+    tracing::info!("提示词美化成功: {} -> {}", payload.prompt, content);
+    Ok(content)
 }
 
+/// Identifies a history group by `id`, or by `timestamp` for clients from
+/// before groups had ids.
 #[derive(Deserialize)]
-struct ExportZipQuery {
-    timestamp: u64,
+struct GroupQuery {
+    id: Option<String>,
+    timestamp: Option<u64>,
+}
+
+impl GroupQuery {
+    async fn find(&self, history: &history::HistoryStore) -> Result<history::GenerationGroup, AppError> {
+        let group = match (&self.id, self.timestamp) {
+            (Some(id), _) => history.find(id).await?,
+            (None, Some(timestamp)) => history.find_by_timestamp(timestamp).await?,
+            (None, None) => return Err(AppError::BadRequest("Either id or timestamp is required".to_string())),
+        };
+        group.ok_or_else(|| AppError::NotFound("History group not found".to_string()))
+    }
 }
 
 async fn export_zip(
     State(state): State<AppState>,
-    Query(query): Query<ExportZipQuery>,
-) -> impl IntoResponse {
-    let history = state.history.read().await;
-    let group = history.iter().find(|g| g.timestamp == query.timestamp);
-
-    if let Some(group) = group {
-        let storage_path = {
-            let config = state.config.read().await;
-            config.storage_path.clone()
-        };
+    Query(query): Query<GroupQuery>,
+) -> Result<Response, AppError> {
+    let group = query.find(&state.history).await?;
 
-        let mut buf = Vec::new();
-        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut buf));
-        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let storage_path = {
+        let config = state.config.read().await;
+        config.storage_path.clone()
+    };
 
-        for img_url in &group.images {
-            if let Some(filename) = img_url.split('/').last() {
-                let path = Path::new(&storage_path).join(filename);
-                if let Ok(data) = tokio::fs::read(path).await {
-                    let _ = zip.start_file(filename, options);
-                    let _ = zip.write_all(&data);
-                }
+    let mut buf = Vec::new();
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut buf));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+    for img_url in &group.images {
+        if let Some(filename) = img_url.split('/').next_back() {
+            let path = Path::new(&storage_path).join(filename);
+            if let Ok(data) = tokio::fs::read(path).await {
+                let _ = zip.start_file(filename, options);
+                let _ = zip.write_all(&data);
             }
         }
+    }
 
-        let _ = zip.finish();
+    zip.finish()
+        .map_err(|e| AppError::Storage(format!("Failed to build zip archive: {}", e)))?;
 
-        Response::builder()
-            .header("Content-Type", "application/zip")
-            .header("Content-Disposition", format!("attachment; filename=\"images-{}.zip\"", query.timestamp))
-            .body(axum::body::Body::from(buf))
-            .unwrap()
-    } else {
-        (StatusCode::NOT_FOUND, "History group not found").into_response()
-    }
+    Ok(Response::builder()
+        .header("Content-Type", "application/zip")
+        .header("Content-Disposition", format!("attachment; filename=\"images-{}.zip\"", group.timestamp))
+        .body(axum::body::Body::from(buf))
+        .unwrap())
 }
 
 async fn generate_image(
     State(state): State<AppState>,
+    Extension(identity): Extension<keys::ClientIdentity>,
     Json(payload): Json<models::openai::ImageGenerationRequest>,
-) -> impl IntoResponse {
-    let (proxy_url, fallback_url, api_key, storage_path, timeout, retry_limit) = {
+) -> Result<Json<models::openai::ImageResponse>, AppError> {
+    tracing::info!("收到图像生成请求 [{}]: {} (n = {})", identity.name, payload.prompt, payload.n);
+    let data = generate_now(&state, &identity, &payload, None).await?;
+    Ok(Json(data))
+}
+
+/// Admits and runs a generation while the client waits, reporting progress
+/// under the request's `task_id` (or a fresh one). The generation runs in
+/// its own task, so a client that disconnects (or a proxy that times out)
+/// does not leave the reservation unsettled; the result still lands in
+/// history.
+async fn generate_now(
+    state: &AppState,
+    identity: &keys::ClientIdentity,
+    payload: &models::openai::ImageGenerationRequest,
+    source_image: Option<String>,
+) -> Result<models::openai::ImageResponse, AppError> {
+    let reservation = admit_generation(state, identity, payload).await?;
+    let task_id = payload.task_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let progress = state.progress.reporter(&task_id, identity.key_id.clone());
+    progress.emit(None, progress::ProgressKind::Queued);
+
+    let task = {
+        let (state, identity, payload) = (state.clone(), identity.clone(), payload.clone());
+        tokio::spawn(async move {
+            complete_generation(&state, &identity, &payload, reservation.as_ref(), &progress, source_image).await
+        })
+    };
+    task.await.map_err(|e| {
+        tracing::error!("生成任务异常退出: {}", e);
+        AppError::Storage("Generation task crashed".to_string())
+    })?
+}
+
+/// Validates a generation request and charges it against the caller's rate
+/// limit and image quota. Admin/UI calls are not subject to client limits.
+async fn admit_generation(
+    state: &AppState,
+    identity: &keys::ClientIdentity,
+    payload: &models::openai::ImageGenerationRequest,
+) -> Result<Option<quota::Reservation>, AppError> {
+    if payload.n == 0 || payload.n > MAX_IMAGES_PER_REQUEST {
+        return Err(AppError::BadRequest(format!("n must be between 1 and {}", MAX_IMAGES_PER_REQUEST)));
+    }
+    if payload.response_format != "url" && payload.response_format != "b64_json" {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported response_format: {} (expected \"url\" or \"b64_json\")",
+            payload.response_format
+        )));
+    }
+    size::resolve(&payload.model, &payload.size)?;
+    let has_reference = payload.image.is_some() || payload.images.as_ref().is_some_and(|i| !i.is_empty());
+    if payload.mask.is_some() && !has_reference {
+        return Err(AppError::BadRequest("A mask needs an image to apply to".to_string()));
+    }
+    let (storage_path, max_upload_bytes) = {
         let config = state.config.read().await;
-        (
-            config.gemini_proxy_url.clone(),
-            config.fallback_proxy_url.clone(),
-            config.api_key.clone(),
-            config.storage_path.clone(),
-            config.timeout,
-            config.retry_limit,
-        )
+        (config.storage_path.clone(), config.max_upload_bytes)
     };
+    references::check(payload, &storage_path, max_upload_bytes).await?;
 
-    tracing::info!("收到图像生成请求: {}", payload.prompt);
-
-    match perform_generation(&state, &proxy_url, &api_key, &payload, &storage_path, timeout, retry_limit).await {
-        Ok(data) => {
-            // 保存到历史记录
-            let images: Vec<String> = data.data.iter().filter_map(|d| d.url.clone()).collect();
-            if !images.is_empty() {
-                let mut history = state.history.write().await;
-                history.insert(0, GenerationGroup {
-                    prompt: payload.prompt.clone(),
-                    timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() * 1000,
-                    images,
-                });
-                if history.len() > 100 { history.truncate(100); }
-                let _ = save_history(&history).await;
-            }
-            (StatusCode::OK, Json(data)).into_response()
-        },
-        Err(e) => {
-            tracing::warn!("主代理失败: {}, 尝试备用代理...", e);
-            if let Some(fallback) = fallback_url {
-                match perform_generation(&state, &fallback, &api_key, &payload, &storage_path, timeout, retry_limit).await {
-                    Ok(data) => {
-                        let images: Vec<String> = data.data.iter().filter_map(|d| d.url.clone()).collect();
-                        if !images.is_empty() {
-                            let mut history = state.history.write().await;
-                            history.insert(0, GenerationGroup {
-                                prompt: payload.prompt.clone(),
-                                timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() * 1000,
-                                images,
-                            });
-                            if history.len() > 100 { history.truncate(100); }
-                            let _ = save_history(&history).await;
-                        }
-                        (StatusCode::OK, Json(data)).into_response()
-                    },
-                    Err(e) => (StatusCode::BAD_GATEWAY, format!("主备代理均失败: {}", e)).into_response()
+    let Some(key_id) = identity.key_id.as_deref() else {
+        return Ok(None);
+    };
+    let limits = state.config.read().await.limits_for(key_id);
+    state.quota.acquire(key_id, &limits, payload.n as u64).await.map(Some)
+}
+
+/// Runs an admitted generation, settles its quota reservation and records
+/// the result in history.
+async fn complete_generation(
+    state: &AppState,
+    identity: &keys::ClientIdentity,
+    payload: &models::openai::ImageGenerationRequest,
+    reservation: Option<&quota::Reservation>,
+    progress: &progress::Reporter,
+    source_image: Option<String>,
+) -> Result<models::openai::ImageResponse, AppError> {
+    let settings = state.config.read().await.generation_settings();
+    let started = std::time::Instant::now();
+
+    let result = match references::prepare(payload, &settings.storage_path, settings.references).await {
+        Ok(resolved) => run_generation(state, &resolved, &settings, progress).await,
+        Err(e) => Err(e),
+    };
+    match &result {
+        Ok(((data, _), _)) => progress.emit(None, progress::ProgressKind::Completed { images: data.data.len() }),
+        Err(e) => progress.emit(None, progress::ProgressKind::failed(e)),
+    }
+    if let Some(reservation) = reservation {
+        let produced = result.as_ref().map(|((d, _), _)| d.data.len() as u64).unwrap_or(0);
+        state.quota.settle(reservation, produced).await;
+    }
+    let ((data, image_details), upstream) = result?;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    // 保存到历史记录
+    let images: Vec<String> = data.data.iter().filter_map(|d| d.url.clone()).collect();
+    if !images.is_empty() {
+        let (references, mask) = references::persist(payload, &settings.storage_path).await.unwrap_or_else(|e| {
+            tracing::warn!("参考图未能记录到历史: {}", e);
+            (Vec::new(), None)
+        });
+        let group = history::GenerationGroup {
+            id: uuid::Uuid::new_v4().to_string(),
+            prompt: payload.prompt.clone(),
+            timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64,
+            images,
+            client_key_id: identity.key_id.clone(),
+            source_image,
+            negative_prompt: payload.negative_prompt.clone().filter(|n| !n.trim().is_empty()),
+            model: Some(payload.model.clone()),
+            size: Some(payload.size.clone()),
+            references,
+            mask,
+            upstream: Some(upstream),
+            attempts: Some(progress.attempts()),
+            latency_ms: Some(latency_ms),
+            image_details,
+        };
+        if let Err(e) = state.history.insert(group).await {
+            tracing::error!("保存历史记录失败: {}", e);
+        }
+    }
+    Ok(data)
+}
+
+/// Tries the upstreams planned for `payload.model` in order until one
+/// produces at least one image. Returns the response with per-image
+/// details, and the upstream used.
+async fn run_generation(
+    state: &AppState,
+    payload: &models::openai::ImageGenerationRequest,
+    settings: &GenerationSettings,
+    progress: &progress::Reporter,
+) -> Result<((models::openai::ImageResponse, Vec<history::ImageRecord>), String), AppError> {
+    with_fallback(state, &payload.model, |target| async move {
+        perform_generation(state, &target, payload, settings, progress).await
+    })
+    .await
+}
+
+/// Runs `call` against the upstreams planned for `model`, best first, until
+/// one succeeds. Returns its result and the name of the upstream used.
+async fn with_fallback<T, F, Fut>(state: &AppState, model: &str, mut call: F) -> Result<(T, String), AppError>
+where
+    F: FnMut(upstream::UpstreamTarget) -> Fut,
+    Fut: std::future::Future<Output = Result<T, AppError>>,
+{
+    let targets = {
+        let config = state.config.read().await;
+        state.upstreams.plan(&config, model).await
+    };
+    if targets.is_empty() {
+        return Err(AppError::Config(format!("No upstream is configured for model {}", model)));
+    }
+
+    let count = targets.len();
+    let mut last_error = None;
+    for (i, target) in targets.into_iter().enumerate() {
+        let name = target.name.clone();
+        match call(target).await {
+            Ok(data) => return Ok((data, name)),
+            Err(e) => {
+                let has_next = i + 1 < count;
+                if !has_next || !worth_another_upstream(&e) {
+                    tracing::error!("上游 {} 失败，不再切换: {}", name, e);
+                    return Err(e);
                 }
-            } else {
-                (StatusCode::BAD_GATEWAY, format!("请求失败且无备用代理: {}", e)).into_response()
+                tracing::warn!("上游 {} 失败: {}, 切换到下一个上游...", name, e);
+                last_error = Some(e);
             }
         }
     }
+    Err(last_error.unwrap_or_else(|| AppError::UpstreamUnavailable("All upstreams failed".to_string())))
 }
 
+/// Errors that another upstream might not reproduce: transient failures,
+/// rejected credentials or unknown models, and unusable responses.
+fn worth_another_upstream(e: &AppError) -> bool {
+    e.is_retryable()
+        || matches!(e, AppError::UpstreamClient { status: 401 | 403 | 404, .. } | AppError::Parse(_))
+}
+
+/// Images from one slot of a batch, with the attempts and milliseconds it took.
+type SlotImages = (Vec<models::openai::ImageData>, usize, u64);
+
+/// Generates `payload.n` images against one upstream, issuing up to
+/// `settings.concurrency` chat completions at a time. Partial successes are
+/// returned with the failed slots listed in `errors`; only a batch where
+/// every slot failed is an `Err`.
 async fn perform_generation(
     state: &AppState,
-    url_str: &str,
-    api_key: &str,
+    target: &upstream::UpstreamTarget,
     payload: &models::openai::ImageGenerationRequest,
-    storage_path: &str,
-    timeout: u64,
-    retry_limit: usize,
-) -> Result<models::openai::ImageResponse, String> {
-    let mut url = if !url_str.starts_with("http://") && !url_str.starts_with("https://") {
-        format!("http://{}", url_str)
-    } else {
-        url_str.to_string()
-    };
+    settings: &GenerationSettings,
+    progress: &progress::Reporter,
+) -> Result<(models::openai::ImageResponse, Vec<history::ImageRecord>), AppError> {
+    let chat_payload = build_chat_payload(payload);
+    let want_b64 = payload.response_format == "b64_json";
 
-    // Standardize URL to always use /v1/chat/completions
-    if url.contains("/images/generations") {
-        url = url.replace("/images/generations", "/chat/completions");
-    } else if !url.ends_with("/chat/completions") {
-        let base = url.trim_end_matches('/');
-        if base.ends_with("/v1") {
-            url = format!("{}/chat/completions", base);
-        } else {
-            url = format!("{}/v1/chat/completions", base);
+    let mut results: Vec<(usize, Result<SlotImages, AppError>)> =
+        futures::stream::iter(0..payload.n)
+            .map(|index| {
+                let chat_payload = &chat_payload;
+                async move {
+                    let started = std::time::Instant::now();
+                    let result = generate_single(state, target, chat_payload, settings, want_b64, index, progress)
+                        .await
+                        .map(|(items, attempts)| (items, attempts, started.elapsed().as_millis() as u64));
+                    (index, result)
+                }
+            })
+            .buffer_unordered(settings.concurrency)
+            .collect()
+            .await;
+
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut data = Vec::new();
+    let mut details = Vec::new();
+    let mut errors = Vec::new();
+    let mut first_error = None;
+    for (index, result) in results {
+        match result {
+            Ok((items, attempts, latency_ms)) => {
+                details.extend(items.iter().map(|item| history::ImageRecord {
+                    id: history::image_id(item.url.as_deref().unwrap_or_default()),
+                    url: item.url.clone().unwrap_or_default(),
+                    revised_prompt: item.revised_prompt.clone(),
+                    attempts: Some(attempts),
+                    latency_ms: Some(latency_ms),
+                }));
+                data.extend(items);
+            }
+            Err(e) => {
+                tracing::warn!("第 {} 张图像生成失败: {}", index + 1, e);
+                progress.emit(Some(index), progress::ProgressKind::failed(&e));
+                errors.push(models::openai::ImageError {
+                    index,
+                    message: e.message().to_string(),
+                    error_type: e.error_type().to_string(),
+                    code: e.code(),
+                });
+                first_error.get_or_insert(e);
+            }
         }
     }
 
+    if data.is_empty() {
+        return Err(first_error.unwrap_or_else(|| AppError::Parse("Upstream returned no images".to_string())));
+    }
+
+    let response = models::openai::ImageResponse {
+        created: now_secs(),
+        data,
+        errors,
+    };
+    Ok((response, details))
+}
+
+fn build_chat_payload(payload: &models::openai::ImageGenerationRequest) -> models::openai::ChatCompletionRequest {
     let mut messages = Vec::new();
     
     // Combine prompt and negative prompt with cleaner formatting
@@ -448,81 +849,242 @@ async fn perform_generation(
         }
     }
 
+    // Chat models have no native mask input, so send it as one more
+    // reference and explain what it is.
+    if let Some(mask) = &payload.mask {
+        content_array.push(serde_json::json!({ "type": "text", "text": MASK_INSTRUCTIONS }));
+        content_array.push(serde_json::json!({ "type": "image_url", "image_url": { "url": mask } }));
+    }
+
     let content = serde_json::Value::Array(content_array);
 
     messages.push(models::openai::ChatMessage {
         role: "user".to_string(),
         content,
+        extra: serde_json::Map::new(),
     });
 
-    let chat_payload = models::openai::ChatCompletionRequest {
+    let mut chat_payload = models::openai::ChatCompletionRequest {
         model: payload.model.clone(),
         messages,
-        temperature: None,
-        max_tokens: None,
+        ..Default::default()
+    };
+
+    // The handler has already rejected sizes the model cannot honour.
+    if let Ok(Some(spec)) = size::resolve(&payload.model, &payload.size) {
+        size::apply(&spec, &mut chat_payload);
+    }
+
+    chat_payload
+}
+
+/// Posts a chat completion to one upstream, retrying transient failures
+/// (network errors, 429, 5xx) with backoff until the retry limit or the
+/// request deadline runs out. Returns the first successful response; the
+/// body has not been read yet, so it can be streamed. `progress` carries
+/// the reporter and batch slot for image generations. Also returns how many
+/// attempts it took.
+async fn send_with_retries(
+    state: &AppState,
+    target: &upstream::UpstreamTarget,
+    chat_payload: &models::openai::ChatCompletionRequest,
+    settings: &GenerationSettings,
+    progress: Option<(&progress::Reporter, usize)>,
+) -> Result<(reqwest::Response, usize), AppError> {
+    let emit = |kind: progress::ProgressKind| {
+        if let Some((reporter, index)) = progress {
+            reporter.emit(Some(index), kind);
+        }
     };
+    let label = progress.map(|(_, index)| format!("图像 #{}", index + 1)).unwrap_or_else(|| "聊天请求".to_string());
 
+    let policy = &settings.retry;
+    let retry_limit = policy.retry_limit;
+    let mut last_error = AppError::UpstreamUnavailable("No attempts were made (retry_limit is 0)".to_string());
+    let mut retry_hint = None;
     for attempt in 0..retry_limit {
-        tracing::info!("🚀 正在尝试生成图像 [第 {}/{} 次] | 目标: {}", attempt + 1, retry_limit, url);
-        
         if attempt > 0 {
-            let delay = 2;
-            tokio::time::sleep(tokio::time::Duration::from_secs(delay)).await;
+            let delay = retry_hint.take().unwrap_or_else(|| policy.backoff(attempt));
+            if !policy.fits(delay) {
+                tracing::warn!("⏱️ {} 已到达请求截止时间，停止重试", label);
+                return Err(AppError::Timeout(format!(
+                    "Request deadline exceeded after {} attempt(s); last error: {}",
+                    attempt, last_error
+                )));
+            }
+            tracing::info!("⏳ {} 将在 {:?} 后重试", label, delay);
+            emit(progress::ProgressKind::Retrying { attempt: attempt + 1, delay_ms: delay.as_millis() as u64 });
+            tokio::time::sleep(delay).await;
         }
 
-        let response = state.client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .timeout(tokio::time::Duration::from_secs(timeout))
-            .json(&chat_payload)
-            .send()
-            .await;
+        if !state.upstreams.allow(&target.name, settings.breaker) {
+            // Let the caller move on to the next upstream right away,
+            // reporting the failure that tripped the breaker if we saw it.
+            return Err(if attempt > 0 {
+                last_error
+            } else {
+                AppError::UpstreamUnavailable(format!("Circuit breaker is open for upstream {}", target.name))
+            });
+        }
+
+        tracing::info!("🚀 正在尝试{} [第 {}/{} 次] | 目标: {} ({})", label, attempt + 1, retry_limit, target.name, target.url);
+        emit(progress::ProgressKind::AttemptStarted {
+            attempt: attempt + 1,
+            max_attempts: retry_limit,
+            upstream: target.name.clone(),
+        });
+
+        let attempt_timeout = tokio::time::Duration::from_secs(settings.timeout).min(policy.remaining());
+        if attempt_timeout.is_zero() {
+            return Err(AppError::Timeout(format!("Request deadline exceeded; last error: {}", last_error)));
+        }
+
+        let request = state.client
+            .post(&target.url)
+            .header("Authorization", format!("Bearer {}", target.api_key))
+            .json(chat_payload);
+        // A stream may rightly outlast any deadline, so for one only the wait
+        // for the headers is bounded; `chat::relay_stream` watches for stalls.
+        let response = if chat_payload.stream {
+            match tokio::time::timeout(attempt_timeout, request.send()).await {
+                Ok(result) => result.map_err(AppError::from),
+                Err(_) => Err(AppError::Timeout(format!(
+                    "Upstream sent no response within {}s",
+                    attempt_timeout.as_secs()
+                ))),
+            }
+        } else {
+            request.timeout(attempt_timeout).send().await.map_err(AppError::from)
+        };
 
         match response {
             Ok(resp) => {
                 let status = resp.status();
                 if status.is_success() {
-                    let chat_resp: models::openai::ChatCompletionResponse = resp.json().await.map_err(|e| e.to_string())?;
-                    let mut image_data_vec = Vec::new();
-                    
-                    for choice in chat_resp.choices {
-                        let content = choice.message.content;
-                        let url = extract_url(&content).ok_or_else(|| format!("无法解析图片地址: {}", content))?;
-
-                        let mut item = models::openai::ImageData {
-                            url: Some(url.clone()),
-                            b64_json: None,
-                            revised_prompt: Some(content.clone()),
-                        };
-
-                        if let Ok(filename) = storage::download_and_save_image(&url, storage_path, &state.client).await {
-                            item.url = Some(format!("/images/{}", filename));
-                        }
-                        
-                        image_data_vec.push(item);
-                    }
-                    
-                    return Ok(models::openai::ImageResponse {
-                        created: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
-                        data: image_data_vec,
-                    });
-                } else {
-                    let error_text = resp.text().await.unwrap_or_default();
-                    tracing::error!("❌ 上游请求失败 | 状态码: {} | 响应: {}", status, error_text);
-                    if status.as_u16() >= 400 && status.as_u16() < 500 && status.as_u16() != 429 {
-                        return Err(format!("客户端请求错误 ({}): {}", status, error_text));
-                    }
-                    continue;
+                    state.upstreams.record_success(&target.name);
+                    return Ok((resp, attempt + 1));
+                }
+                if matches!(status.as_u16(), 429 | 503) {
+                    retry_hint = retry::retry_after(resp.headers());
+                }
+                let error_text = resp.text().await.unwrap_or_default();
+                tracing::error!("❌ 上游请求失败 | 状态码: {} | 响应: {}", status, error_text);
+                let mut error = AppError::from_upstream_status(status, &error_text);
+                if let AppError::UpstreamClient { retry_after, .. } = &mut error {
+                    *retry_after = retry_hint.map(|delay| delay.as_secs());
                 }
+                emit(progress::ProgressKind::upstream_error(attempt + 1, &target.name, &error));
+                if !error.is_retryable() {
+                    // The upstream is up; it just rejected this request.
+                    state.upstreams.record_success(&target.name);
+                    return Err(error);
+                }
+                state.upstreams.record_failure(&target.name, settings.breaker);
+                last_error = error;
             }
             Err(e) => {
                 tracing::warn!("⚠️ 网络请求异常: {} | 将进行下一次重试", e);
-                if attempt == retry_limit - 1 { return Err(e.to_string()); }
-                continue;
+                state.upstreams.record_failure(&target.name, settings.breaker);
+                last_error = e;
+                emit(progress::ProgressKind::upstream_error(attempt + 1, &target.name, &last_error));
             }
         }
     }
-    Err("重试耗尽".to_string())
+    Err(last_error)
+}
+
+/// One upstream chat completion (with retries) for a single slot of the
+/// batch. Returns its images and the attempts it took.
+async fn generate_single(
+    state: &AppState,
+    target: &upstream::UpstreamTarget,
+    chat_payload: &models::openai::ChatCompletionRequest,
+    settings: &GenerationSettings,
+    want_b64: bool,
+    index: usize,
+    progress: &progress::Reporter,
+) -> Result<(Vec<models::openai::ImageData>, usize), AppError> {
+    let (resp, attempts) = send_with_retries(state, target, chat_payload, settings, Some((progress, index))).await?;
+    let chat_resp: models::openai::ChatCompletionResponse = resp.json().await?;
+    let mut image_data_vec = Vec::new();
+
+    for choice in chat_resp.choices {
+        let content = choice.message.content;
+        let Some(url) = extract_url(&content) else {
+            discard_saved(&settings.storage_path, &image_data_vec).await;
+            return Err(AppError::Parse(format!(
+                "Could not find an image in upstream response: {}",
+                truncate_for_log(&content)
+            )));
+        };
+
+        let mut item = models::openai::ImageData {
+            url: Some(url.clone()),
+            b64_json: None,
+            revised_prompt: Some(content.clone()),
+        };
+
+        let inline = !storage::is_remote(&url);
+        progress.emit(Some(index), progress::ProgressKind::Downloading);
+        match storage::save_image_source(&url, &settings.storage_path, &state.client).await {
+            Ok(saved) => {
+                tracing::info!("💾 图像已保存: {} ({})", saved.filename, saved.mime_type);
+                let filename = saved.filename;
+                let local_url = format!("/images/{}", filename);
+                if inline {
+                    // Don't echo a multi-megabyte base64 payload back as the revised prompt.
+                    item.revised_prompt = Some(content.replace(&url, &local_url));
+                }
+                progress.emit(Some(index), progress::ProgressKind::Saved { url: local_url.clone() });
+                item.url = Some(local_url);
+                if want_b64 {
+                    match read_as_b64(&settings.storage_path, &filename, settings.max_b64_bytes).await {
+                        Ok(b64) => item.b64_json = Some(b64),
+                        Err(e) => {
+                            image_data_vec.push(item);
+                            discard_saved(&settings.storage_path, &image_data_vec).await;
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            Err(e) if want_b64 || inline => {
+                discard_saved(&settings.storage_path, &image_data_vec).await;
+                return Err(e);
+            }
+            Err(e) => tracing::warn!("图像转存失败，返回上游地址: {}", e),
+        }
+
+        image_data_vec.push(item);
+    }
+
+    Ok((image_data_vec, attempts))
+}
+
+/// Removes the files already saved for a slot that fails after all; no
+/// history row will ever point at them.
+async fn discard_saved(storage_path: &str, items: &[models::openai::ImageData]) {
+    for url in items.iter().filter_map(|item| item.url.as_deref()) {
+        if let Err(e) = storage::remove_stored(storage_path, url).await {
+            tracing::warn!("清理未使用的图像失败: {}", e);
+        }
+    }
+}
+
+/// Reads a stored image back for `response_format: "b64_json"`, refusing
+/// files larger than `max_bytes` so a single response stays bounded.
+async fn read_as_b64(storage_path: &str, filename: &str, max_bytes: u64) -> Result<String, AppError> {
+    let path = Path::new(storage_path).join(filename);
+    let read_error = |e: std::io::Error| AppError::Storage(format!("Failed to read stored image: {}", e));
+    let size = tokio::fs::metadata(&path).await.map_err(read_error)?.len();
+    if size > max_bytes {
+        return Err(AppError::BadRequest(format!(
+            "Image is {} bytes, which exceeds the b64_json limit of {} bytes; use response_format \"url\" instead",
+            size, max_bytes
+        )));
+    }
+    let bytes = tokio::fs::read(&path).await.map_err(read_error)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
 }
 
 fn extract_url(content: &str) -> Option<String> {
@@ -536,13 +1098,76 @@ fn extract_url(content: &str) -> Option<String> {
             return Some(url.trim_matches(|c| c == '"' || c == '\'').to_string());
         }
     }
-    
-    if let Some(start) = content.find("http") {
+
+    if let Some(start) = content.find("data:image/") {
         let sub = &content[start..];
         let end = sub.find(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == ')' || c == ']')
             .unwrap_or(sub.len());
         return Some(sub[..end].to_string());
     }
     
-    None
-}
\ No newline at end of file
+    // Some proxies answer with nothing but the raw base64 of the image, which
+    // may well contain "http" by chance; check for that before bare URLs.
+    let trimmed = content.trim();
+    if trimmed.len() >= 64
+        && trimmed.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '-' | '_' | '\n' | '\r'))
+    {
+        return Some(trimmed.to_string());
+    }
+
+    // A bare URL, only where a token starts.
+    let start = content.match_indices("http").map(|(i, _)| i).find(|&i| {
+        let rest = &content[i..];
+        let at_boundary = content[..i].chars().next_back().is_none_or(|c| !c.is_ascii_alphanumeric());
+        at_boundary && (rest.starts_with("http://") || rest.starts_with("https://"))
+    })?;
+    let sub = &content[start..];
+    let end = sub.find(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == ')' || c == ']')
+        .unwrap_or(sub.len());
+    Some(sub[..end].to_string())
+}
+
+fn truncate_for_log(content: &str) -> String {
+    const LIMIT: usize = 200;
+    match content.char_indices().nth(LIMIT) {
+        Some((idx, _)) => format!("{}…", &content[..idx]),
+        None => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_url_reads_a_markdown_link() {
+        assert_eq!(
+            extract_url("Here you go: ![image](https://cdn.example.com/a.png \"title\") enjoy").as_deref(),
+            Some("https://cdn.example.com/a.png")
+        );
+    }
+
+    #[test]
+    fn extract_url_reads_a_data_uri() {
+        assert_eq!(
+            extract_url("Result:\ndata:image/png;base64,iVBORw0KGgo= done").as_deref(),
+            Some("data:image/png;base64,iVBORw0KGgo=")
+        );
+    }
+
+    #[test]
+    fn extract_url_keeps_raw_base64_that_contains_http() {
+        let raw = format!("iVBORw0KGgoAAAANSUhEUgAAhttpAAAB{}", "A".repeat(64));
+        assert_eq!(extract_url(&format!("{}\n", raw)), Some(raw));
+    }
+
+    #[test]
+    fn extract_url_reads_a_bare_url_only_at_a_token_boundary() {
+        assert_eq!(
+            extract_url("See https://cdn.example.com/b.jpg for the result").as_deref(),
+            Some("https://cdn.example.com/b.jpg")
+        );
+        assert_eq!(extract_url("the xhttp://nope and httpfoo are not links"), None);
+        assert_eq!(extract_url("no image here"), None);
+    }
+}
@@ -14,6 +14,13 @@ pub struct ImageGenerationRequest {
     pub size: String,
     #[serde(default = "default_response_format")]
     pub response_format: String,
+    /// Edit mask for the first reference image; transparent areas mark
+    /// where the image should change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<String>,
+    /// Client-chosen id for following progress on `/v1/images/events`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
 }
 
 fn default_model() -> String { "gemini-3-pro-image".to_string() }
@@ -25,6 +32,18 @@ fn default_response_format() -> String { "url".to_string() }
 pub struct ImageResponse {
     pub created: u64,
     pub data: Vec<ImageData>,
+    /// Per-image failures when only part of an `n > 1` batch succeeded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ImageError>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImageError {
+    pub index: usize,
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub code: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,20 +55,67 @@ pub struct ImageData {
 
 // --- Chat Completion Structures (for upstream proxy requests and responses) ---
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// OpenAI chat completion parameters. Fields this server does not know
+/// about are kept in `extra` and forwarded to the upstream unchanged.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ChatCompletionRequest {
+    #[serde(default)]
     pub model: String,
     pub messages: Vec<ChatMessage>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    /// A string or an array of up to four strings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_body: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub role: String,
+    /// `null` for assistant messages that only carry `tool_calls`.
+    #[serde(default)]
     pub content: serde_json::Value,
+    /// `name`, `tool_calls`, `tool_call_id` and any other message fields.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
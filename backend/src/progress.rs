@@ -0,0 +1,220 @@
+//! Per-task generation progress, streamed to clients as Server-Sent Events
+//! from `GET /v1/images/events`. A task is one `/v1/images/generations`
+//! call (identified by its `task_id`) or one queued job (its job id).
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Extension, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{error::AppError, extract::Query, keys::ClientIdentity, AppState};
+
+/// Recent events kept so a subscriber that connects just after a task
+/// started still sees its earlier steps.
+const REPLAY_EVENTS: usize = 512;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressKind {
+    Queued,
+    AttemptStarted { attempt: usize, max_attempts: usize, upstream: String },
+    UpstreamError { attempt: usize, upstream: String, message: String, code: String },
+    Retrying { attempt: usize, delay_ms: u64 },
+    Downloading,
+    Saved { url: String },
+    Failed { message: String, code: String },
+    Completed { images: usize },
+    Cancelled,
+}
+
+impl ProgressKind {
+    pub fn failed(e: &AppError) -> Self {
+        ProgressKind::Failed { message: e.message().to_string(), code: e.code() }
+    }
+
+    pub fn upstream_error(attempt: usize, upstream: &str, e: &AppError) -> Self {
+        ProgressKind::UpstreamError {
+            attempt,
+            upstream: upstream.to_string(),
+            message: e.message().to_string(),
+            code: e.code(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProgressEvent {
+    pub seq: u64,
+    pub task_id: String,
+    /// Image slot within an `n > 1` batch; absent for task-level events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub timestamp: u64,
+    #[serde(skip)]
+    client_key_id: Option<String>,
+    #[serde(flatten)]
+    pub kind: ProgressKind,
+}
+
+impl ProgressEvent {
+    /// Ends a task's stream: nothing more will be reported for it.
+    fn is_terminal(&self) -> bool {
+        self.index.is_none()
+            && matches!(self.kind, ProgressKind::Completed { .. } | ProgressKind::Failed { .. } | ProgressKind::Cancelled)
+    }
+
+    fn visible_to(&self, identity: &ClientIdentity) -> bool {
+        identity.key_id.is_none() || identity.key_id == self.client_key_id
+    }
+}
+
+pub struct ProgressHub {
+    sender: broadcast::Sender<ProgressEvent>,
+    recent: Mutex<VecDeque<ProgressEvent>>,
+    seq: AtomicU64,
+}
+
+impl Default for ProgressHub {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        ProgressHub { sender, recent: Mutex::new(VecDeque::new()), seq: AtomicU64::new(0) }
+    }
+}
+
+impl ProgressHub {
+    pub fn reporter(self: &Arc<Self>, task_id: &str, client_key_id: Option<String>) -> Reporter {
+        Reporter {
+            hub: self.clone(),
+            task_id: task_id.to_string(),
+            client_key_id,
+            attempts: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn publish(&self, mut event: ProgressEvent) {
+        let mut recent = self.recent.lock().unwrap();
+        event.seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        if recent.len() == REPLAY_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // No subscribers is fine; the event is still kept for replay.
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Emits events for one task.
+#[derive(Clone)]
+pub struct Reporter {
+    hub: Arc<ProgressHub>,
+    task_id: String,
+    client_key_id: Option<String>,
+    /// Upstream requests made so far, across slots and upstreams.
+    attempts: Arc<AtomicUsize>,
+}
+
+impl Reporter {
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    pub fn emit(&self, index: Option<usize>, kind: ProgressKind) {
+        if matches!(kind, ProgressKind::AttemptStarted { .. }) {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+        }
+        self.hub.publish(ProgressEvent {
+            seq: 0,
+            task_id: self.task_id.clone(),
+            index,
+            timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64,
+            client_key_id: self.client_key_id.clone(),
+            kind,
+        });
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Only stream this task, and close once it finishes. Without it, every
+    /// task visible to the caller is streamed.
+    task_id: Option<String>,
+}
+
+pub async fn stream_events(
+    State(state): State<AppState>,
+    Extension(identity): Extension<ClientIdentity>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let hub = state.progress.clone();
+    let single_task = query.task_id.is_some();
+    let task_id = query.task_id;
+    let wanted = move |event: &ProgressEvent| {
+        event.visible_to(&identity) && task_id.as_ref().is_none_or(|id| *id == event.task_id)
+    };
+    // Subscribe before snapshotting the replay buffer so nothing falls in
+    // between; duplicates are dropped by sequence number below.
+    let receiver = hub.sender.subscribe();
+    let replay: VecDeque<ProgressEvent> = if single_task {
+        hub.recent.lock().unwrap().iter().filter(|e| wanted(e)).cloned().collect()
+    } else {
+        VecDeque::new()
+    };
+    let last_seq = replay.back().map(|e| e.seq).unwrap_or(0);
+
+    let stream = futures::stream::unfold(
+        (replay, receiver, last_seq, false),
+        move |(mut replay, mut receiver, last_seq, done)| {
+            let wanted = wanted.clone();
+            async move {
+                if done {
+                    return None;
+                }
+                let event = match replay.pop_front() {
+                    Some(event) => event,
+                    None => loop {
+                        match receiver.recv().await {
+                            Ok(event) if event.seq > last_seq && wanted(&event) => break event,
+                            Ok(_) => continue,
+                            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                tracing::warn!("进度订阅者落后，丢弃 {} 条事件", skipped);
+                                continue;
+                            }
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    },
+                };
+                let done = single_task && event.is_terminal();
+                let sse = Event::default()
+                    .id(event.seq.to_string())
+                    .event(event_name(&event.kind))
+                    .json_data(&event)
+                    .unwrap_or_default();
+                Some((Ok(sse), (replay, receiver, last_seq, done)))
+            }
+        },
+    );
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+fn event_name(kind: &ProgressKind) -> &'static str {
+    match kind {
+        ProgressKind::Queued => "queued",
+        ProgressKind::AttemptStarted { .. } => "attempt_started",
+        ProgressKind::UpstreamError { .. } => "upstream_error",
+        ProgressKind::Retrying { .. } => "retrying",
+        ProgressKind::Downloading => "downloading",
+        ProgressKind::Saved { .. } => "saved",
+        ProgressKind::Failed { .. } => "failed",
+        ProgressKind::Completed { .. } => "completed",
+        ProgressKind::Cancelled => "cancelled",
+    }
+}
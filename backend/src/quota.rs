@@ -0,0 +1,316 @@
+//! Per-client rate limiting (token bucket) and daily/monthly image quotas.
+//! Bucket state lives in memory; image usage is persisted to `usage.json`
+//! so quotas survive a restart.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, now_secs};
+
+const USAGE_FILE: &str = "usage.json";
+
+/// Limits applied to a client key. `None` means unlimited.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ClientLimits {
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    /// Bucket capacity; defaults to `requests_per_minute`.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub daily_images: Option<u64>,
+    #[serde(default)]
+    pub monthly_images: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Usage {
+    /// Days since the Unix epoch (UTC) that `day_images` counts.
+    day: u64,
+    day_images: u64,
+    /// `year * 12 + (month - 1)` that `month_images` counts.
+    month: u64,
+    month_images: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    buckets: HashMap<String, Bucket>,
+    usage: HashMap<String, Usage>,
+}
+
+pub struct QuotaTracker {
+    inner: Mutex<Inner>,
+    /// Serializes writes of `usage.json` so the last one holds the newest usage.
+    saving: tokio::sync::Mutex<()>,
+}
+
+/// Images reserved against a key's quota; hand back unused ones with
+/// [`QuotaTracker::settle`] once the generation finishes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reservation {
+    key_id: String,
+    images: u64,
+    /// The day and month the images were counted in; a refund only applies
+    /// while those periods are still current.
+    #[serde(default)]
+    day: u64,
+    #[serde(default)]
+    month: u64,
+}
+
+/// (year, month) for a count of days since 1970-01-01, per Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+/// Days since 1970-01-01 for the first day of `year`/`month`.
+fn days_from_civil(year: u64, month: u64) -> u64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn month_index(days: u64) -> u64 {
+    let (year, month) = civil_from_days(days);
+    year * 12 + (month - 1)
+}
+
+fn secs_until_next_month(now: u64) -> u64 {
+    let (year, month) = civil_from_days(now / 86_400);
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    days_from_civil(next_year, next_month) * 86_400 - now
+}
+
+impl Usage {
+    fn roll(&mut self, now: u64) {
+        let day = now / 86_400;
+        if self.day != day {
+            self.day = day;
+            self.day_images = 0;
+        }
+        let month = month_index(day);
+        if self.month != month {
+            self.month = month;
+            self.month_images = 0;
+        }
+    }
+
+    /// Hands back the unproduced part of `reservation`, in whichever of its
+    /// periods are still current.
+    fn refund(&mut self, reservation: &Reservation, produced: u64) {
+        let refund = reservation.images.saturating_sub(produced);
+        if self.day == reservation.day {
+            self.day_images = self.day_images.saturating_sub(refund);
+        }
+        if self.month == reservation.month {
+            self.month_images = self.month_images.saturating_sub(refund);
+        }
+    }
+}
+
+impl QuotaTracker {
+    pub async fn load() -> Self {
+        let usage = match tokio::fs::read_to_string(USAGE_FILE).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        Self { inner: Mutex::new(Inner { buckets: HashMap::new(), usage }), saving: tokio::sync::Mutex::new(()) }
+    }
+
+    async fn save(&self) {
+        let _saving = self.saving.lock().await;
+        let snapshot = serde_json::to_string_pretty(&self.inner.lock().unwrap().usage).unwrap();
+        if let Err(e) = tokio::fs::write(USAGE_FILE, snapshot).await {
+            tracing::error!("保存用量记录失败: {}", e);
+        }
+    }
+
+    /// Takes one request token and reserves `images` against the quotas,
+    /// persisting the usage when images were reserved.
+    pub async fn acquire(&self, key_id: &str, limits: &ClientLimits, images: u64) -> Result<Reservation, AppError> {
+        let reservation = self.reserve(key_id, limits, images)?;
+        if images > 0 {
+            self.save().await;
+        }
+        Ok(reservation)
+    }
+
+    fn reserve(&self, key_id: &str, limits: &ClientLimits, images: u64) -> Result<Reservation, AppError> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        // The token is only taken once the quotas also allow the request.
+        let mut bucket = None;
+        if let Some(rpm) = limits.requests_per_minute.filter(|r| *r > 0) {
+            let capacity = f64::from(limits.burst.unwrap_or(rpm).max(1));
+            let rate = f64::from(rpm) / 60.0;
+            let refilled = inner
+                .buckets
+                .entry(key_id.to_string())
+                .or_insert_with(|| Bucket { tokens: capacity, refilled_at: Instant::now() });
+            let elapsed = refilled.refilled_at.elapsed().as_secs_f64();
+            refilled.tokens = (refilled.tokens + elapsed * rate).min(capacity);
+            refilled.refilled_at = Instant::now();
+            if refilled.tokens < 1.0 {
+                let retry_after = ((1.0 - refilled.tokens) / rate).ceil() as u64;
+                return Err(AppError::RateLimited {
+                    message: format!("Rate limit of {} requests per minute exceeded", rpm),
+                    retry_after: retry_after.max(1),
+                });
+            }
+            bucket = Some(refilled);
+        }
+
+        let now = now_secs();
+        let usage = inner.usage.entry(key_id.to_string()).or_default();
+        usage.roll(now);
+        if let Some(daily) = limits.daily_images {
+            if usage.day_images + images > daily {
+                return Err(AppError::RateLimited {
+                    message: format!("Daily quota of {} images exhausted ({} used)", daily, usage.day_images),
+                    retry_after: 86_400 - now % 86_400,
+                });
+            }
+        }
+        if let Some(monthly) = limits.monthly_images {
+            if usage.month_images + images > monthly {
+                return Err(AppError::RateLimited {
+                    message: format!("Monthly quota of {} images exhausted ({} used)", monthly, usage.month_images),
+                    retry_after: secs_until_next_month(now),
+                });
+            }
+        }
+        if let Some(bucket) = bucket {
+            bucket.tokens -= 1.0;
+        }
+        usage.day_images += images;
+        usage.month_images += images;
+
+        Ok(Reservation { key_id: key_id.to_string(), images, day: usage.day, month: usage.month })
+    }
+
+    /// Refunds the part of a reservation that did not produce images and
+    /// persists the updated usage. A day or month that has rolled over since
+    /// the reservation is left alone, as its count no longer includes it.
+    pub async fn settle(&self, reservation: &Reservation, produced: u64) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(usage) = inner.usage.get_mut(&reservation.key_id) {
+                usage.refund(reservation, produced);
+            }
+        }
+        self.save().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86_400;
+
+    #[test]
+    fn calendar_conversions_cross_month_and_year_boundaries() {
+        let cases = [
+            (0, (1970, 1)),
+            (11_016, (2000, 2)), // 2000-02-29, a leap day in a century year
+            (19_722, (2023, 12)),
+            (19_723, (2024, 1)),
+            (19_416, (2023, 2)), // 2023-02-28
+            (19_417, (2023, 3)),
+            (19_782, (2024, 2)), // 2024-02-29
+            (19_783, (2024, 3)),
+            (47_540, (2100, 2)), // 2100-02-28; 2100 is not a leap year
+            (47_541, (2100, 3)),
+        ];
+        for (days, civil) in cases {
+            assert_eq!(civil_from_days(days), civil, "day {}", days);
+        }
+
+        assert_eq!(days_from_civil(1970, 1), 0);
+        assert_eq!(days_from_civil(2024, 1), 19_723);
+        assert_eq!(days_from_civil(2024, 3), 19_783);
+        assert_eq!(days_from_civil(2100, 3), 47_541);
+        assert_eq!(days_from_civil(2000, 3), 11_017);
+    }
+
+    #[test]
+    fn next_month_is_counted_from_now() {
+        // Last second of 2023-12-31.
+        assert_eq!(secs_until_next_month(19_723 * DAY - 1), 1);
+        // Start of 2024-02-01: February has 29 days in 2024.
+        assert_eq!(secs_until_next_month(19_754 * DAY), 29 * DAY);
+        // Midday on 2023-02-28.
+        assert_eq!(secs_until_next_month(19_416 * DAY + DAY / 2), DAY / 2);
+    }
+
+    #[test]
+    fn roll_resets_only_the_periods_that_changed() {
+        let mut usage = Usage::default();
+        usage.roll(19_752 * DAY); // 2024-01-30
+        usage.day_images = 3;
+        usage.month_images = 5;
+
+        usage.roll(19_752 * DAY + 100);
+        assert_eq!((usage.day_images, usage.month_images), (3, 5));
+
+        usage.roll(19_753 * DAY); // 2024-01-31
+        assert_eq!((usage.day_images, usage.month_images), (0, 5));
+
+        usage.day_images = 2;
+        usage.roll(19_754 * DAY); // 2024-02-01
+        assert_eq!((usage.day_images, usage.month_images), (0, 0));
+        assert_eq!(usage.month, 2024 * 12 + 1);
+    }
+
+    #[test]
+    fn refund_applies_only_within_the_reserved_periods() {
+        let mut usage = Usage::default();
+        usage.roll(19_752 * DAY); // 2024-01-30
+        usage.day_images = 4;
+        usage.month_images = 4;
+        let reservation = Reservation { key_id: "k".to_string(), images: 4, day: usage.day, month: usage.month };
+
+        let mut same_day = usage.clone();
+        same_day.refund(&reservation, 1);
+        assert_eq!((same_day.day_images, same_day.month_images), (1, 1));
+
+        // Next day, same month: the new day's count never included the reservation.
+        let mut next_day = usage.clone();
+        next_day.roll(19_753 * DAY);
+        next_day.day_images = 2;
+        next_day.month_images = 6;
+        next_day.refund(&reservation, 1);
+        assert_eq!((next_day.day_images, next_day.month_images), (2, 3));
+
+        // Next month: neither count is touched.
+        let mut next_month = usage.clone();
+        next_month.roll(19_754 * DAY);
+        next_month.day_images = 2;
+        next_month.month_images = 2;
+        next_month.refund(&reservation, 0);
+        assert_eq!((next_month.day_images, next_month.month_images), (2, 2));
+    }
+}
@@ -0,0 +1,235 @@
+//! Reference images (`image`, `images`, `mask`) in generation requests.
+//! Besides data URLs and remote URLs, clients may point at an image already
+//! in `storage_path` by `/images/<file>` or bare id. Before a request goes
+//! upstream every local or inline reference is decoded, downscaled,
+//! re-encoded (which also drops EXIF and other metadata) and inlined as a
+//! data URL; remote URLs are passed through for the upstream to fetch.
+
+use std::io::Cursor;
+
+use image::{imageops::FilterType, DynamicImage, ImageReader, Limits, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, models::openai::ImageGenerationRequest, storage};
+
+/// Longest string treated as a bare image id rather than inline base64.
+const MAX_ID_LEN: usize = 128;
+
+/// Reference images (`image` plus `images`) accepted in one request.
+pub const MAX_REFERENCES: usize = 16;
+
+/// Refuse to decode anything larger than this on either side, whatever the
+/// configured downscale target; guards against decompression bombs.
+const MAX_DECODE_DIMENSION: u32 = 16_384;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferenceFormat {
+    #[default]
+    Jpeg,
+    Webp,
+    Png,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReferenceSettings {
+    /// Longest side after downscaling; 0 keeps the original size.
+    pub max_dimension: u32,
+    pub format: ReferenceFormat,
+    /// 1-100, for JPEG and WebP.
+    pub quality: u8,
+    /// Largest encoded reference accepted from a client.
+    pub max_bytes: u64,
+}
+
+/// Whether `source` names an image in storage rather than carrying one.
+fn is_stored(source: &str) -> bool {
+    let source = source.trim();
+    source.starts_with("/images/")
+        || (!source.is_empty()
+            && source.len() <= MAX_ID_LEN
+            && source.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+}
+
+/// Field name of each reference, for error messages.
+fn labelled(payload: &ImageGenerationRequest) -> Vec<(String, &String)> {
+    let mut sources = Vec::new();
+    if let Some(image) = &payload.image {
+        sources.push(("image".to_string(), image));
+    }
+    for (i, image) in payload.images.iter().flatten().enumerate() {
+        sources.push((format!("images[{}]", i), image));
+    }
+    if let Some(mask) = &payload.mask {
+        sources.push(("mask".to_string(), mask));
+    }
+    sources
+}
+
+async fn load(label: &str, source: &str, storage_path: &str, max_bytes: u64) -> Result<Vec<u8>, AppError> {
+    let bytes = if is_stored(source) {
+        let filename = storage::find_stored(storage_path, source).await?;
+        storage::read_stored(storage_path, &filename).await?
+    } else {
+        storage::decode_inline(source).map_err(|e| AppError::BadRequest(format!("{}: {}", label, e.message())))?
+    };
+    if bytes.len() as u64 > max_bytes {
+        return Err(AppError::BadRequest(format!(
+            "{} is {} bytes, which exceeds the limit of {} bytes",
+            label,
+            bytes.len(),
+            max_bytes
+        )));
+    }
+    storage::sniff_format(&bytes).map_err(|e| AppError::BadRequest(format!("{}: {}", label, e.message())))?;
+    Ok(bytes)
+}
+
+/// Request body cap for the JSON generation routes: `max_request_bytes` of
+/// inline references, base64 adding a third, plus room for the other fields.
+pub fn json_body_limit(max_request_bytes: u64) -> usize {
+    let inline = max_request_bytes.div_ceil(3).saturating_mul(4);
+    usize::try_from(inline.saturating_add(1024 * 1024)).unwrap_or(usize::MAX)
+}
+
+/// Cheap validation at admission time, before quota is spent or a job is
+/// queued: stored references exist, inline ones decode to a supported format
+/// within the size limit.
+pub async fn check(payload: &ImageGenerationRequest, storage_path: &str, max_bytes: u64) -> Result<(), AppError> {
+    let count = usize::from(payload.image.is_some()) + payload.images.as_ref().map_or(0, Vec::len);
+    if count > MAX_REFERENCES {
+        return Err(AppError::BadRequest(format!("At most {} reference images are accepted", MAX_REFERENCES)));
+    }
+    for (label, source) in labelled(payload) {
+        if !storage::is_remote(source) {
+            load(&label, source, storage_path, max_bytes).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy of `payload` with every local or inline reference preprocessed and
+/// inlined as a data URL.
+pub async fn prepare(
+    payload: &ImageGenerationRequest,
+    storage_path: &str,
+    settings: ReferenceSettings,
+) -> Result<ImageGenerationRequest, AppError> {
+    let mut prepared = Vec::new();
+    for (label, source) in labelled(payload) {
+        if storage::is_remote(source) {
+            prepared.push(source.clone());
+            continue;
+        }
+        let bytes = load(&label, source, storage_path, settings.max_bytes).await?;
+        // Masks keep their alpha channel, which is what marks the edit region.
+        let format = if label == "mask" { ReferenceFormat::Png } else { settings.format };
+        let data_url = tokio::task::spawn_blocking(move || reencode(&label, &bytes, settings, format))
+            .await
+            .map_err(|e| AppError::Storage(format!("Reference preprocessing failed: {}", e)))??;
+        prepared.push(data_url);
+    }
+
+    let mut resolved = payload.clone();
+    let mut prepared = prepared.into_iter();
+    if let Some(image) = resolved.image.as_mut() {
+        *image = prepared.next().unwrap_or_default();
+    }
+    for image in resolved.images.iter_mut().flatten() {
+        *image = prepared.next().unwrap_or_default();
+    }
+    if let Some(mask) = resolved.mask.as_mut() {
+        *mask = prepared.next().unwrap_or_default();
+    }
+    Ok(resolved)
+}
+
+/// Where the references (and the mask) of `payload` can be found later, for
+/// history: stored images as `/images/<file>`, inline ones after saving the
+/// original bytes to storage (content-addressed, so repeated uploads share a
+/// file), remote URLs as given.
+pub async fn persist(payload: &ImageGenerationRequest, storage_path: &str) -> Result<(Vec<String>, Option<String>), AppError> {
+    let mut references = Vec::new();
+    let mut mask = None;
+    for (label, source) in labelled(payload) {
+        let kept = if storage::is_remote(source) {
+            source.clone()
+        } else if is_stored(source) {
+            format!("/images/{}", storage::find_stored(storage_path, source).await?)
+        } else {
+            let bytes = storage::decode_inline(source)?;
+            format!("/images/{}", storage::write_image_deduplicated(storage_path, &bytes).await?.filename)
+        };
+        if label == "mask" {
+            mask = Some(kept);
+        } else {
+            references.push(kept);
+        }
+    }
+    Ok((references, mask))
+}
+
+fn reencode(label: &str, bytes: &[u8], settings: ReferenceSettings, format: ReferenceFormat) -> Result<String, AppError> {
+    let invalid = |e: image::ImageError| AppError::BadRequest(format!("{} could not be decoded: {}", label, e));
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODE_DIMENSION);
+    limits.max_image_height = Some(MAX_DECODE_DIMENSION);
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().map_err(|e| invalid(e.into()))?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(invalid)?;
+    // Re-encoding drops EXIF, so bake the camera orientation into the pixels first.
+    let orientation = image::ImageDecoder::orientation(&mut decoder).map_err(invalid)?;
+    let mut img = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+    img.apply_orientation(orientation);
+
+    let (width, height) = (img.width(), img.height());
+    if settings.max_dimension > 0 && width.max(height) > settings.max_dimension {
+        img = img.resize(settings.max_dimension, settings.max_dimension, FilterType::Lanczos3);
+        tracing::debug!("参考图 {} 已缩放: {}x{} -> {}x{}", label, width, height, img.width(), img.height());
+    }
+
+    let quality = settings.quality.clamp(1, 100);
+    let encode_error = |e: image::ImageError| AppError::Storage(format!("Failed to re-encode {}: {}", label, e));
+    let (mime_type, encoded) = match format {
+        ReferenceFormat::Jpeg => {
+            let mut buf = Vec::new();
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, quality);
+            flatten_alpha(&img).write_with_encoder(encoder).map_err(encode_error)?;
+            ("image/jpeg", buf)
+        }
+        ReferenceFormat::Webp => {
+            let memory = if img.color().has_alpha() {
+                let rgba = img.to_rgba8();
+                webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode(f32::from(quality))
+            } else {
+                let rgb = img.to_rgb8();
+                webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height()).encode(f32::from(quality))
+            };
+            ("image/webp", memory.to_vec())
+        }
+        ReferenceFormat::Png => {
+            let mut buf = Cursor::new(Vec::new());
+            img.write_to(&mut buf, image::ImageFormat::Png).map_err(encode_error)?;
+            ("image/png", buf.into_inner())
+        }
+    };
+
+    tracing::debug!("参考图 {}: {} 字节 -> {} 字节 ({})", label, bytes.len(), encoded.len(), mime_type);
+    Ok(format!("data:{};base64,{}", mime_type, base64::Engine::encode(&base64::engine::general_purpose::STANDARD, encoded)))
+}
+
+/// JPEG has no alpha channel; composite transparent areas onto white rather
+/// than letting them turn black.
+fn flatten_alpha(img: &DynamicImage) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return DynamicImage::ImageRgb8(img.to_rgb8());
+    }
+    let rgba = img.to_rgba8();
+    let flattened = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((u16::from(c) * u16::from(a) + 255 * (255 - u16::from(a))) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    });
+    DynamicImage::ImageRgb8(flattened)
+}
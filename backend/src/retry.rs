@@ -0,0 +1,110 @@
+//! Retry pacing for upstream calls: exponential backoff with jitter, upstream
+//! `Retry-After` hints, and an overall deadline per client request.
+
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use tokio::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub retry_limit: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Point after which no further attempts are started.
+    pub deadline: Instant,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (1-based): `base * 2^(attempt-1)`,
+    /// capped at `max_delay`, with "equal jitter" so concurrent slots that
+    /// failed together do not retry in lockstep.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1).min(16) as u32;
+        let ceiling = self.base_delay.saturating_mul(1 << exp).min(self.max_delay);
+        let half = ceiling / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Whether waiting `delay` would still leave time for another attempt.
+    pub fn fits(&self, delay: Duration) -> bool {
+        Instant::now() + delay < self.deadline
+    }
+}
+
+/// Parses `Retry-After` given in seconds. HTTP-date values are ignored and
+/// fall back to the regular backoff.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            retry_limit: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            deadline: Instant::now() + Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_within_jitter_bounds() {
+        let policy = policy();
+        for (attempt, ceiling_ms) in [(0, 100), (1, 100), (2, 200), (3, 400), (4, 800), (5, 1600)] {
+            let ceiling = Duration::from_millis(ceiling_ms);
+            for _ in 0..50 {
+                let delay = policy.backoff(attempt);
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "attempt {}: {:?}", attempt, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max_delay() {
+        let policy = policy();
+        for attempt in [6, 17, 64, usize::MAX] {
+            let delay = policy.backoff(attempt);
+            assert!(delay >= policy.max_delay / 2 && delay <= policy.max_delay, "attempt {}: {:?}", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn fits_respects_the_deadline() {
+        let policy = policy();
+        assert!(policy.fits(Duration::from_secs(1)));
+        assert!(!policy.fits(Duration::from_secs(61)));
+        assert!(policy.remaining() <= Duration::from_secs(60));
+    }
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_reads_seconds_only() {
+        assert_eq!(retry_after(&headers("3")), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! Translation of OpenAI-style `size` values onto what each upstream model
+//! understands. Gemini image models take an aspect ratio (and, for 3 Pro, an
+//! output resolution) through the OpenAI-compat `extra_body`; anything we do
+//! not recognise gets the ratio appended to the prompt instead.
+
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::models::openai::ChatCompletionRequest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SizeStrategy {
+    /// `extra_body.google.image_config`, as accepted by the Gemini OpenAI-compat layer.
+    ExtraBody,
+    /// Plain-text hint appended to the prompt.
+    PromptSuffix,
+}
+
+struct ModelSizeProfile {
+    prefix: &'static str,
+    strategy: SizeStrategy,
+    aspect_ratios: &'static [&'static str],
+    supports_resolution: bool,
+}
+
+const GEMINI_RATIOS: &[&str] = &["1:1", "2:3", "3:2", "3:4", "4:3", "4:5", "5:4", "9:16", "16:9", "21:9"];
+const COMMON_RATIOS: &[&str] = &["1:1", "2:3", "3:2", "3:4", "4:3", "9:16", "16:9"];
+
+/// Matched by prefix, first hit wins; the last entry is the catch-all.
+const PROFILES: &[ModelSizeProfile] = &[
+    ModelSizeProfile { prefix: "gemini-3-pro-image", strategy: SizeStrategy::ExtraBody, aspect_ratios: GEMINI_RATIOS, supports_resolution: true },
+    ModelSizeProfile { prefix: "gemini-2.5-flash-image", strategy: SizeStrategy::ExtraBody, aspect_ratios: GEMINI_RATIOS, supports_resolution: false },
+    ModelSizeProfile { prefix: "gemini-2.0-flash", strategy: SizeStrategy::PromptSuffix, aspect_ratios: COMMON_RATIOS, supports_resolution: false },
+    ModelSizeProfile { prefix: "", strategy: SizeStrategy::PromptSuffix, aspect_ratios: COMMON_RATIOS, supports_resolution: false },
+];
+
+/// How far (relative) a requested ratio may be from a supported one, so that
+/// sizes like `1216x896` still land on 4:3.
+const RATIO_TOLERANCE: f64 = 0.05;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeSpec {
+    pub aspect_ratio: &'static str,
+    pub image_size: Option<&'static str>,
+    /// Original `WxH` value, when the client sent pixel dimensions.
+    pub pixels: Option<String>,
+}
+
+fn profile_for(model: &str) -> &'static ModelSizeProfile {
+    PROFILES
+        .iter()
+        .find(|p| model.starts_with(p.prefix))
+        .unwrap_or(&PROFILES[PROFILES.len() - 1])
+}
+
+fn parse_pair(value: &str, sep: char) -> Option<(f64, f64)> {
+    let (w, h) = value.split_once(sep)?;
+    let w: f64 = w.trim().parse().ok()?;
+    let h: f64 = h.trim().parse().ok()?;
+    (w > 0.0 && h > 0.0).then_some((w, h))
+}
+
+fn ratio_value(ratio: &str) -> f64 {
+    parse_pair(ratio, ':').map(|(w, h)| w / h).unwrap_or(1.0)
+}
+
+/// Resolves a `size` value (`WxH`, `W:H` or `auto`) for `model`.
+/// Returns `Ok(None)` when the upstream default should be used.
+pub fn resolve(model: &str, size: &str) -> Result<Option<SizeSpec>, AppError> {
+    let size = size.trim().to_ascii_lowercase();
+    if size.is_empty() || size == "auto" {
+        return Ok(None);
+    }
+
+    let (pixels, (w, h)) = if let Some(dims) = parse_pair(&size, 'x') {
+        (Some(size.clone()), dims)
+    } else if let Some(ratio) = parse_pair(&size, ':') {
+        (None, ratio)
+    } else {
+        return Err(AppError::BadRequest(format!("Unrecognised size: {}", size)));
+    };
+
+    let profile = profile_for(model);
+    let requested = w / h;
+    let (aspect_ratio, distance) = profile
+        .aspect_ratios
+        .iter()
+        .map(|r| (*r, (ratio_value(r) / requested).ln().abs()))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .ok_or_else(|| AppError::BadRequest(format!("Model {} does not support custom sizes", model)))?;
+
+    if distance > (1.0 + RATIO_TOLERANCE).ln() {
+        return Err(AppError::BadRequest(format!(
+            "Model {} does not support size {}; supported aspect ratios: {}",
+            model,
+            size,
+            profile.aspect_ratios.join(", ")
+        )));
+    }
+
+    let image_size = match pixels {
+        Some(_) if profile.supports_resolution => {
+            let longest = w.max(h);
+            Some(if longest <= 1536.0 { "1K" } else if longest <= 3072.0 { "2K" } else { "4K" })
+        }
+        _ => None,
+    };
+
+    Ok(Some(SizeSpec { aspect_ratio, image_size, pixels }))
+}
+
+/// Writes `spec` into the chat request using the model's strategy.
+pub fn apply(spec: &SizeSpec, chat_payload: &mut ChatCompletionRequest) {
+    match profile_for(&chat_payload.model).strategy {
+        SizeStrategy::ExtraBody => {
+            let mut image_config = json!({ "aspect_ratio": spec.aspect_ratio });
+            if let Some(image_size) = spec.image_size {
+                image_config["image_size"] = json!(image_size);
+            }
+            let mut extra_body = json!({ "google": { "image_config": image_config } });
+            if let Some(pixels) = &spec.pixels {
+                extra_body["size"] = json!(pixels);
+            }
+            chat_payload.extra_body = Some(extra_body);
+        }
+        SizeStrategy::PromptSuffix => {
+            let hint = format!("\nAspect ratio: {}", spec.aspect_ratio);
+            if let Some(message) = chat_payload.messages.last_mut() {
+                if let Some(text) = message
+                    .content
+                    .as_array_mut()
+                    .and_then(|parts| parts.iter_mut().find(|p| p["type"] == "text"))
+                    .and_then(|part| part.get_mut("text"))
+                {
+                    if let Some(existing) = text.as_str() {
+                        *text = json!(format!("{}{}", existing, hint));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(model: &str) -> ChatCompletionRequest {
+        serde_json::from_value(json!({
+            "model": model,
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "a cat" }] }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolve_matches_within_tolerance() {
+        let spec = resolve("gemini-2.5-flash-image", "1216x896").unwrap().unwrap();
+        assert_eq!(spec.aspect_ratio, "4:3");
+        assert_eq!(spec.image_size, None);
+        assert_eq!(spec.pixels.as_deref(), Some("1216x896"));
+
+        let spec = resolve("gemini-2.5-flash-image", " 16:9 ").unwrap().unwrap();
+        assert_eq!((spec.aspect_ratio, spec.pixels), ("16:9", None));
+
+        assert_eq!(resolve("gemini-2.5-flash-image", "auto").unwrap(), None);
+        assert_eq!(resolve("gemini-2.5-flash-image", "").unwrap(), None);
+    }
+
+    #[test]
+    fn resolve_rejects_unsupported_sizes() {
+        let error = resolve("gemini-2.5-flash-image", "1000x200").unwrap_err();
+        assert!(error.message().contains("supported aspect ratios"), "{}", error);
+        // 21:9 is a Gemini ratio, not one every model has.
+        assert!(resolve("gemini-2.5-flash-image", "21:9").is_ok());
+        assert!(resolve("some-other-model", "21:9").is_err());
+        assert!(resolve("gemini-2.5-flash-image", "large").is_err());
+        assert!(resolve("gemini-2.5-flash-image", "0x512").is_err());
+    }
+
+    #[test]
+    fn resolve_picks_the_image_size_tier() {
+        let tier = |size: &str| resolve("gemini-3-pro-image-preview", size).unwrap().unwrap().image_size;
+        assert_eq!(tier("1024x1024"), Some("1K"));
+        assert_eq!(tier("1536x1024"), Some("1K"));
+        assert_eq!(tier("2048x1536"), Some("2K"));
+        assert_eq!(tier("3072x2048"), Some("2K"));
+        assert_eq!(tier("4096x4096"), Some("4K"));
+        // A bare ratio says nothing about resolution.
+        assert_eq!(tier("1:1"), None);
+    }
+
+    #[test]
+    fn apply_uses_extra_body_for_gemini() {
+        let spec = resolve("gemini-3-pro-image-preview", "2048x1536").unwrap().unwrap();
+        let mut payload = chat("gemini-3-pro-image-preview");
+        apply(&spec, &mut payload);
+        assert_eq!(
+            payload.extra_body,
+            Some(json!({
+                "google": { "image_config": { "aspect_ratio": "4:3", "image_size": "2K" } },
+                "size": "2048x1536",
+            }))
+        );
+        assert_eq!(payload.messages[0].content[0]["text"], "a cat");
+    }
+
+    #[test]
+    fn apply_appends_the_ratio_to_the_prompt_for_unknown_models() {
+        let spec = resolve("some-other-model", "16:9").unwrap().unwrap();
+        let mut payload = chat("some-other-model");
+        apply(&spec, &mut payload);
+        assert_eq!(payload.extra_body, None);
+        assert_eq!(payload.messages[0].content[0]["text"], "a cat\nAspect ratio: 16:9");
+    }
+}
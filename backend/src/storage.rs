@@ -2,46 +2,234 @@ use uuid::Uuid;
 use std::path::Path;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use base64::Engine;
+use image::ImageFormat;
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+
+/// A file written to `storage_path`, with the format sniffed from its bytes.
+#[derive(Debug, Clone)]
+pub struct SavedImage {
+    pub filename: String,
+    pub mime_type: &'static str,
+}
+
+/// Whether `source` has to be fetched over HTTP, as opposed to being inline data.
+pub fn is_remote(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Stores an image returned by the upstream, which may be an HTTP URL, a
+/// `data:image/...;base64,` URI or a bare base64 blob.
+pub async fn save_image_source(
+    source: &str,
+    storage_path: &str,
+    client: &reqwest::Client,
+) -> Result<SavedImage, AppError> {
+    if is_remote(source) {
+        return download_and_save_image(source, storage_path, client).await;
+    }
+
+    let bytes = decode_inline(source)?;
+    write_image(storage_path, &bytes).await
+}
+
+/// Decodes a `data:...;base64,` URI or a bare base64 blob.
+pub fn decode_inline(source: &str) -> Result<Vec<u8>, AppError> {
+    let encoded = if let Some(rest) = source.strip_prefix("data:") {
+        let (meta, data) = rest
+            .split_once(',')
+            .ok_or_else(|| AppError::Parse("Malformed data URI".to_string()))?;
+        if !meta.ends_with(";base64") {
+            return Err(AppError::Parse(format!("Unsupported data URI encoding: {}", meta)));
+        }
+        data
+    } else {
+        source
+    };
+    decode_base64(encoded)
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, AppError> {
+    let cleaned: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+    let unpadded = cleaned.trim_end_matches('=');
+    base64::engine::general_purpose::STANDARD_NO_PAD
+        .decode(unpadded)
+        .or_else(|_| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(unpadded))
+        .map_err(|e| AppError::Parse(format!("Failed to decode base64 image: {}", e)))
+}
+
+/// Identifies the image format from its magic bytes. Anything that is not one
+/// of the formats we serve (e.g. an HTML error page from a CDN) is rejected.
+pub fn sniff_format(bytes: &[u8]) -> Result<(&'static str, &'static str), AppError> {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Png) => Ok(("png", "image/png")),
+        Ok(ImageFormat::Jpeg) => Ok(("jpg", "image/jpeg")),
+        Ok(ImageFormat::WebP) => Ok(("webp", "image/webp")),
+        Ok(ImageFormat::Gif) => Ok(("gif", "image/gif")),
+        Ok(other) => Err(AppError::Parse(format!("Unsupported image format: {:?}", other))),
+        Err(_) => {
+            let preview: String = String::from_utf8_lossy(&bytes[..bytes.len().min(64)])
+                .chars()
+                .filter(|c| !c.is_control())
+                .collect();
+            Err(AppError::Parse(format!("Payload is not an image ({} bytes, starts with {:?})", bytes.len(), preview)))
+        }
+    }
+}
 
 pub async fn download_and_save_image(
     url: &str,
     storage_path: &str,
     client: &reqwest::Client,
-) -> Result<String, String> {
-    let response = client
-        .get(url)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to download image: {}", e))?;
+) -> Result<SavedImage, AppError> {
+    let response = client.get(url).send().await?;
 
     if !response.status().is_success() {
-        return Err(format!("Server returned error status: {}", response.status()));
+        return Err(AppError::from_upstream_status(response.status(), "image download failed"));
     }
 
-    let bytes = response
-        .bytes()
-        .await
-        .map_err(|e| format!("Failed to read image bytes: {}", e))?;
+    let bytes = response.bytes().await?;
 
-    // Determine extension from URL or default to png
-    let extension = if url.contains(".webp") {
-        "webp"
-    } else if url.contains(".jpg") || url.contains(".jpeg") {
-        "jpg"
-    } else {
-        "png"
-    };
+    write_image(storage_path, &bytes).await
+}
 
+pub async fn write_image(storage_path: &str, bytes: &[u8]) -> Result<SavedImage, AppError> {
+    let (extension, mime_type) = sniff_format(bytes)?;
     let filename = format!("{}.{}", Uuid::new_v4(), extension);
     let path = Path::new(storage_path).join(&filename);
 
     let mut file = File::create(path)
         .await
-        .map_err(|e| format!("Failed to create file: {}", e))?;
+        .map_err(|e| AppError::Storage(format!("Failed to create file: {}", e)))?;
 
-    file.write_all(&bytes)
+    file.write_all(bytes)
         .await
-        .map_err(|e| format!("Failed to write to file: {}", e))?;
+        .map_err(|e| AppError::Storage(format!("Failed to write to file: {}", e)))?;
+
+    Ok(SavedImage { filename, mime_type })
+}
+
+/// Stores `bytes` under a name derived from their SHA-256, so the same
+/// image uploaded again reuses the existing file.
+pub async fn write_image_deduplicated(storage_path: &str, bytes: &[u8]) -> Result<SavedImage, AppError> {
+    let (extension, mime_type) = sniff_format(bytes)?;
+    let digest: String = Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect();
+    let filename = format!("{}.{}", digest, extension);
+    let path = Path::new(storage_path).join(&filename);
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        tokio::fs::write(&path, bytes)
+            .await
+            .map_err(|e| AppError::Storage(format!("Failed to write to file: {}", e)))?;
+    }
+    Ok(SavedImage { filename, mime_type })
+}
 
-    Ok(filename)
+/// Image extensions `write_image` produces, tried in order for bare ids.
+const STORED_EXTENSIONS: &[&str] = &["png", "jpg", "webp", "gif"];
+
+/// Rejects names that could point outside `storage_path` (separators, `..`,
+/// hidden files); `reference` is what the client sent, for the error.
+fn check_stored_name(name: &str, reference: &str) -> Result<(), AppError> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(AppError::BadRequest(format!("Invalid image reference: {:?}", reference)));
+    }
+    Ok(())
+}
+
+/// Resolves a reference to an image in `storage_path` — `/images/<file>`,
+/// `<file>` or a bare id (the file stem) — to its filename.
+pub async fn find_stored(storage_path: &str, reference: &str) -> Result<String, AppError> {
+    let name = reference.trim();
+    let name = name.strip_prefix("/images/").unwrap_or(name);
+    check_stored_name(name, reference)?;
+
+    let candidates: Vec<String> = if Path::new(name).extension().is_some() {
+        vec![name.to_string()]
+    } else {
+        STORED_EXTENSIONS.iter().map(|ext| format!("{}.{}", name, ext)).collect()
+    };
+    for filename in candidates {
+        if tokio::fs::try_exists(Path::new(storage_path).join(&filename)).await.unwrap_or(false) {
+            return Ok(filename);
+        }
+    }
+    Err(AppError::NotFound(format!("Stored image {} not found", reference)))
+}
+
+/// Deletes the file behind an `/images/<file>` URL. Other URLs (an upstream
+/// address kept when saving failed) have no local file and are skipped, as
+/// are files that are already gone.
+pub async fn remove_stored(storage_path: &str, url: &str) -> Result<(), AppError> {
+    let Some(name) = url.strip_prefix("/images/") else {
+        return Ok(());
+    };
+    check_stored_name(name, url)?;
+    match tokio::fs::remove_file(Path::new(storage_path).join(name)).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(AppError::Storage(format!("Failed to delete stored image {}: {}", name, e))),
+    }
+}
+
+pub async fn read_stored(storage_path: &str, filename: &str) -> Result<Vec<u8>, AppError> {
+    tokio::fs::read(Path::new(storage_path).join(filename))
+        .await
+        .map_err(|e| AppError::Storage(format!("Failed to read stored image {}: {}", filename, e)))
+}
+
+/// Encodes image bytes as a `data:` URL for an upstream `image_url` part.
+pub fn to_data_url(bytes: &[u8]) -> Result<String, AppError> {
+    let (_, mime_type) = sniff_format(bytes)?;
+    Ok(format!("data:{};base64,{}", mime_type, base64::engine::general_purpose::STANDARD.encode(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let mut buf = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(2, 2).write_to(&mut buf, format).unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn sniff_format_recognizes_png_and_jpeg() {
+        assert_eq!(sniff_format(&encoded(ImageFormat::Png)).unwrap(), ("png", "image/png"));
+        assert_eq!(sniff_format(&encoded(ImageFormat::Jpeg)).unwrap(), ("jpg", "image/jpeg"));
+    }
+
+    #[test]
+    fn sniff_format_rejects_an_html_error_page() {
+        let page = b"<!DOCTYPE html>\n<html><head><title>502 Bad Gateway</title></head></html>";
+        let error = sniff_format(page).unwrap_err();
+        assert!(matches!(error, AppError::Parse(_)));
+        assert!(error.message().contains("<!DOCTYPE html>"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn remove_stored_rejects_paths_outside_storage() {
+        let root = std::env::temp_dir().join(format!("storage-test-{}", Uuid::new_v4()));
+        let storage = root.join("storage");
+        std::fs::create_dir_all(&storage).unwrap();
+        std::fs::write(root.join("secret.txt"), "keep").unwrap();
+        std::fs::write(storage.join("a.png"), "png").unwrap();
+        let storage_path = storage.to_str().unwrap();
+
+        for url in ["/images/../secret.txt", "/images/..\\secret.txt", "/images/sub/a.png", "/images/.hidden", "/images/"] {
+            let result = remove_stored(storage_path, url).await;
+            assert!(matches!(result, Err(AppError::BadRequest(_))), "{}: {:?}", url, result);
+        }
+        assert!(root.join("secret.txt").exists());
+
+        // Remote URLs are not ours to delete, and a missing file is fine.
+        remove_stored(storage_path, "https://example.com/a.png").await.unwrap();
+        remove_stored(storage_path, "/images/missing.png").await.unwrap();
+        remove_stored(storage_path, "/images/a.png").await.unwrap();
+        assert!(!storage.join("a.png").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
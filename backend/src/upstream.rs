@@ -0,0 +1,565 @@
+//! Pool of upstream proxies: per-upstream credentials, weights and model
+//! allowlists from `Config::upstreams`, active health probing, per-upstream
+//! circuit breakers, and the order in which a request should try them.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    error::AppError,
+    extract::{Json, Path},
+    AppState,
+    Config,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpstreamConfig {
+    /// Unique name, used in logs, history and the admin API.
+    pub name: String,
+    pub url: String,
+    /// Falls back to `Config::api_key` when unset.
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Lower tiers are preferred; higher tiers are only used as fallbacks.
+    #[serde(default)]
+    pub priority: u32,
+    /// Models this upstream may serve; empty means any.
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Probe URL; defaults to the upstream's `/v1/models`.
+    #[serde(default)]
+    pub health_url: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_weight() -> u32 { 1 }
+fn default_enabled() -> bool { true }
+
+/// A resolved upstream to send one request to.
+#[derive(Clone, Debug)]
+pub struct UpstreamTarget {
+    pub name: String,
+    pub url: String,
+    pub api_key: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UpstreamHealth {
+    pub healthy: bool,
+    pub last_checked: u64,
+    pub latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// Breaker tuning, snapshotted from `Config` per request.
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerSettings {
+    /// Consecutive failures that trip the breaker; 0 disables it.
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Breaker {
+    Closed { failures: u32 },
+    /// Rejecting calls until `until`, then one trial call is let through.
+    Open { until: Instant },
+    /// A trial call is in flight since `since`; its outcome closes or
+    /// re-opens the breaker. If it never reports back (e.g. the request was
+    /// dropped), another trial is allowed after a further cooldown.
+    HalfOpen { since: Instant },
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker::Closed { failures: 0 }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BreakerStatus {
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    /// Seconds until an open breaker lets a trial call through.
+    pub retry_in_secs: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct UpstreamPool {
+    health: RwLock<HashMap<String, UpstreamHealth>>,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+/// Turns a configured proxy address into its `/v1/chat/completions` endpoint.
+pub fn chat_url(url_str: &str) -> String {
+    let mut url = if !url_str.starts_with("http://") && !url_str.starts_with("https://") {
+        format!("http://{}", url_str)
+    } else {
+        url_str.to_string()
+    };
+
+    // Standardize URL to always use /v1/chat/completions
+    if url.contains("/images/generations") {
+        url = url.replace("/images/generations", "/chat/completions");
+    } else if !url.ends_with("/chat/completions") {
+        let base = url.trim_end_matches('/');
+        if base.ends_with("/v1") {
+            url = format!("{}/chat/completions", base);
+        } else {
+            url = format!("{}/v1/chat/completions", base);
+        }
+    }
+    url
+}
+
+fn models_url(upstream: &UpstreamConfig) -> String {
+    let chat = chat_url(&upstream.url);
+    format!("{}/models", chat.trim_end_matches("/chat/completions"))
+}
+
+fn health_url(upstream: &UpstreamConfig) -> String {
+    upstream.health_url.clone().unwrap_or_else(|| models_url(upstream))
+}
+
+impl Config {
+    /// Configured upstreams, or the legacy primary/fallback pair when
+    /// `upstreams` is empty.
+    pub fn upstream_list(&self) -> Vec<UpstreamConfig> {
+        if !self.upstreams.is_empty() {
+            return self.upstreams.clone();
+        }
+        let legacy = |name: &str, url: &str, priority| UpstreamConfig {
+            name: name.to_string(),
+            url: url.to_string(),
+            api_key: None,
+            weight: 1,
+            priority,
+            models: Vec::new(),
+            health_url: None,
+            enabled: true,
+        };
+        let mut list = vec![legacy("primary", &self.gemini_proxy_url, 0)];
+        if let Some(fallback) = self.fallback_proxy_url.as_deref().filter(|u| !u.trim().is_empty()) {
+            list.push(legacy("fallback", fallback, 1));
+        }
+        list
+    }
+}
+
+/// Weighted random permutation: each pick is proportional to weight.
+fn weighted_shuffle(mut items: Vec<UpstreamConfig>) -> Vec<UpstreamConfig> {
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(items.len());
+    while !items.is_empty() {
+        let total: u64 = items.iter().map(|u| u64::from(u.weight.max(1))).sum();
+        let mut pick = rng.gen_range(0..total);
+        let idx = items
+            .iter()
+            .position(|u| {
+                let w = u64::from(u.weight.max(1));
+                if pick < w { true } else { pick -= w; false }
+            })
+            .unwrap_or(0);
+        ordered.push(items.remove(idx));
+    }
+    ordered
+}
+
+impl UpstreamPool {
+    /// Whether a call to `name` may go out now. An open breaker whose cooldown
+    /// has elapsed moves to half-open and admits exactly one trial call.
+    pub fn allow(&self, name: &str, settings: BreakerSettings) -> bool {
+        if settings.failure_threshold == 0 {
+            return true;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(name.to_string()).or_default();
+        match *breaker {
+            Breaker::Closed { .. } => true,
+            Breaker::Open { until } if Instant::now() >= until => {
+                tracing::info!("🔌 上游 {} 熔断冷却结束，放行一次试探请求", name);
+                *breaker = Breaker::HalfOpen { since: Instant::now() };
+                true
+            }
+            Breaker::HalfOpen { since } if since.elapsed() >= settings.cooldown => {
+                *breaker = Breaker::HalfOpen { since: Instant::now() };
+                true
+            }
+            Breaker::Open { .. } | Breaker::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self, name: &str) {
+        let mut breakers = self.breakers.lock().unwrap();
+        if let Some(breaker) = breakers.get_mut(name) {
+            if matches!(breaker, Breaker::HalfOpen { .. }) {
+                tracing::info!("✅ 上游 {} 试探成功，熔断器关闭", name);
+            }
+            *breaker = Breaker::Closed { failures: 0 };
+        }
+    }
+
+    pub fn record_failure(&self, name: &str, settings: BreakerSettings) {
+        if settings.failure_threshold == 0 {
+            return;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(name.to_string()).or_default();
+        let trip = match *breaker {
+            Breaker::Closed { failures } if failures + 1 >= settings.failure_threshold => true,
+            Breaker::Closed { failures } => {
+                *breaker = Breaker::Closed { failures: failures + 1 };
+                false
+            }
+            Breaker::HalfOpen { .. } => true,
+            // Late failures from calls started before the breaker opened.
+            Breaker::Open { .. } => false,
+        };
+        if trip {
+            tracing::warn!("⛔ 上游 {} 熔断器打开，{:?} 内将直接跳过", name, settings.cooldown);
+            *breaker = Breaker::Open { until: Instant::now() + settings.cooldown };
+        }
+    }
+
+    fn is_open(&self, name: &str) -> bool {
+        matches!(
+            self.breakers.lock().unwrap().get(name),
+            Some(Breaker::Open { until }) if Instant::now() < *until
+        )
+    }
+
+    pub fn breaker_status(&self, name: &str) -> BreakerStatus {
+        let breaker = self.breakers.lock().unwrap().get(name).copied().unwrap_or_default();
+        match breaker {
+            Breaker::Closed { failures } => BreakerStatus { state: "closed", consecutive_failures: failures, retry_in_secs: None },
+            Breaker::Open { until } => BreakerStatus {
+                state: "open",
+                consecutive_failures: 0,
+                retry_in_secs: Some(until.saturating_duration_since(Instant::now()).as_secs()),
+            },
+            Breaker::HalfOpen { .. } => BreakerStatus { state: "half_open", consecutive_failures: 0, retry_in_secs: None },
+        }
+    }
+
+    pub fn reset_breaker(&self, name: &str) {
+        self.breakers.lock().unwrap().insert(name.to_string(), Breaker::default());
+    }
+
+    async fn is_healthy(&self, name: &str) -> bool {
+        // Upstreams that have not been probed yet are assumed healthy.
+        self.health.read().await.get(name).map(|h| h.healthy).unwrap_or(true)
+    }
+
+    /// Upstreams to try for `model`, best first: healthy ones by priority
+    /// tier with weighted random order inside a tier, then unhealthy ones and
+    /// finally ones with an open breaker, as a last resort.
+    pub async fn plan(&self, config: &Config, model: &str) -> Vec<UpstreamTarget> {
+        let mut healthy: Vec<UpstreamConfig> = Vec::new();
+        let mut unhealthy: Vec<UpstreamConfig> = Vec::new();
+        let mut tripped: Vec<UpstreamConfig> = Vec::new();
+        for upstream in config.upstream_list() {
+            if !upstream.enabled || (!upstream.models.is_empty() && !upstream.models.iter().any(|m| m == model)) {
+                continue;
+            }
+            if self.is_open(&upstream.name) {
+                tripped.push(upstream);
+            } else if self.is_healthy(&upstream.name).await {
+                healthy.push(upstream);
+            } else {
+                unhealthy.push(upstream);
+            }
+        }
+
+        let mut tiers: Vec<u32> = healthy.iter().map(|u| u.priority).collect();
+        tiers.sort_unstable();
+        tiers.dedup();
+
+        let mut ordered = Vec::new();
+        for tier in tiers {
+            let members = healthy.iter().filter(|u| u.priority == tier).cloned().collect();
+            ordered.extend(weighted_shuffle(members));
+        }
+        unhealthy.sort_by_key(|u| u.priority);
+        ordered.extend(unhealthy);
+        tripped.sort_by_key(|u| u.priority);
+        ordered.extend(tripped);
+
+        ordered
+            .into_iter()
+            .map(|u| UpstreamTarget {
+                api_key: u.api_key.clone().unwrap_or_else(|| config.api_key.clone()),
+                url: chat_url(&u.url),
+                name: u.name,
+            })
+            .collect()
+    }
+
+    async fn probe(&self, client: &reqwest::Client, upstream: &UpstreamConfig, api_key: &str) {
+        let started = std::time::Instant::now();
+        let result = client
+            .get(health_url(upstream))
+            .header("Authorization", format!("Bearer {}", api_key))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await;
+
+        let (healthy, last_error) = match result {
+            Ok(resp) if resp.status().is_success() => (true, None),
+            Ok(resp) => (false, Some(format!("Health check returned {}", resp.status()))),
+            Err(e) => (false, Some(e.to_string())),
+        };
+
+        let mut health = self.health.write().await;
+        let was_healthy = health.get(&upstream.name).map(|h| h.healthy).unwrap_or(true);
+        if was_healthy != healthy {
+            if healthy {
+                tracing::info!("✅ 上游 {} 恢复健康", upstream.name);
+            } else {
+                tracing::warn!("🩺 上游 {} 健康检查失败: {}", upstream.name, last_error.as_deref().unwrap_or_default());
+            }
+        }
+        health.insert(upstream.name.clone(), UpstreamHealth {
+            healthy,
+            last_checked: crate::now_secs(),
+            latency_ms: healthy.then(|| started.elapsed().as_millis() as u64),
+            last_error,
+        });
+    }
+
+    pub async fn snapshot(&self) -> HashMap<String, UpstreamHealth> {
+        self.health.read().await.clone()
+    }
+}
+
+/// Probes every enabled upstream every `health_check_interval` seconds.
+/// An interval of 0 disables probing.
+pub fn spawn_health_checks(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            let (upstreams, api_key, interval) = {
+                let config = state.config.read().await;
+                (config.upstream_list(), config.api_key.clone(), config.health_check_interval)
+            };
+            if interval == 0 {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                continue;
+            }
+
+            let probes = upstreams.iter().filter(|u| u.enabled).map(|u| {
+                let key = u.api_key.clone().unwrap_or_else(|| api_key.clone());
+                let pool = state.upstreams.clone();
+                let client = state.client.clone();
+                async move { pool.probe(&client, u, &key).await }
+            });
+            futures::future::join_all(probes).await;
+
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct UpstreamStatus {
+    name: String,
+    url: String,
+    weight: u32,
+    priority: u32,
+    models: Vec<String>,
+    enabled: bool,
+    health: Option<UpstreamHealth>,
+    breaker: BreakerStatus,
+}
+
+/// Admin view of the pool and the latest probe results.
+pub async fn list_upstreams(State(state): State<AppState>) -> impl IntoResponse {
+    let upstreams = state.config.read().await.upstream_list();
+    let health = state.upstreams.snapshot().await;
+    let statuses: Vec<UpstreamStatus> = upstreams
+        .into_iter()
+        .map(|u| UpstreamStatus {
+            health: health.get(&u.name).cloned(),
+            breaker: state.upstreams.breaker_status(&u.name),
+            name: u.name,
+            url: u.url,
+            weight: u.weight,
+            priority: u.priority,
+            models: u.models,
+            enabled: u.enabled,
+        })
+        .collect();
+    Json(statuses)
+}
+
+/// Closes an upstream's breaker by hand, e.g. after fixing the proxy.
+pub async fn reset_upstream_breaker(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let known = state.config.read().await.upstream_list().iter().any(|u| u.name == name);
+    if !known {
+        return Err(AppError::NotFound(format!("Upstream {} not found", name)));
+    }
+    state.upstreams.reset_breaker(&name);
+    tracing::info!("🔌 上游 {} 熔断器已手动重置", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct ModelEntry {
+    id: String,
+    object: &'static str,
+    created: u64,
+    owned_by: String,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelId>,
+}
+
+#[derive(Deserialize)]
+struct ModelId {
+    id: String,
+}
+
+/// Model ids an upstream serves: its allowlist if it has one, otherwise
+/// whatever its `/models` endpoint reports.
+async fn upstream_models(client: &reqwest::Client, upstream: &UpstreamConfig, api_key: &str) -> Vec<String> {
+    if !upstream.models.is_empty() {
+        return upstream.models.clone();
+    }
+    let result = async {
+        client
+            .get(models_url(upstream))
+            .header("Authorization", format!("Bearer {}", api_key))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ModelList>()
+            .await
+    }
+    .await;
+    match result {
+        Ok(list) => list.data.into_iter().map(|m| m.id).collect(),
+        Err(e) => {
+            tracing::warn!("获取上游 {} 的模型列表失败: {}", upstream.name, e);
+            Vec::new()
+        }
+    }
+}
+
+/// `/v1/models`: the union of the models offered by enabled upstreams whose
+/// breaker is closed, in OpenAI's list format.
+pub async fn list_models(State(state): State<AppState>) -> impl IntoResponse {
+    let (upstreams, default_key) = {
+        let config = state.config.read().await;
+        (config.upstream_list(), config.api_key.clone())
+    };
+    let upstreams: Vec<UpstreamConfig> = upstreams
+        .into_iter()
+        .filter(|u| u.enabled && !state.upstreams.is_open(&u.name))
+        .collect();
+
+    let listings = futures::future::join_all(upstreams.iter().map(|u| {
+        let api_key = u.api_key.as_deref().unwrap_or(&default_key);
+        upstream_models(&state.client, u, api_key)
+    }))
+    .await;
+
+    let created = crate::now_secs();
+    let mut seen = HashSet::new();
+    let mut data = Vec::new();
+    for (upstream, ids) in upstreams.iter().zip(listings) {
+        for id in ids {
+            if seen.insert(id.clone()) {
+                data.push(ModelEntry { id, object: "model", created, owned_by: upstream.name.clone() });
+            }
+        }
+    }
+    Json(serde_json::json!({ "object": "list", "data": data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: BreakerSettings = BreakerSettings { failure_threshold: 2, cooldown: Duration::from_millis(50) };
+
+    fn state(pool: &UpstreamPool) -> &'static str {
+        pool.breaker_status("a").state
+    }
+
+    fn cool_down() {
+        std::thread::sleep(SETTINGS.cooldown + Duration::from_millis(10));
+    }
+
+    /// Trips the breaker and waits out its cooldown.
+    fn tripped() -> UpstreamPool {
+        let pool = UpstreamPool::default();
+        pool.record_failure("a", SETTINGS);
+        assert_eq!(state(&pool), "closed");
+        pool.record_failure("a", SETTINGS);
+        assert_eq!(state(&pool), "open");
+        assert!(!pool.allow("a", SETTINGS));
+        cool_down();
+        pool
+    }
+
+    #[test]
+    fn successful_trial_closes_the_breaker() {
+        let pool = tripped();
+        assert!(pool.allow("a", SETTINGS));
+        assert_eq!(state(&pool), "half_open");
+        // Only one trial at a time.
+        assert!(!pool.allow("a", SETTINGS));
+
+        pool.record_success("a");
+        assert_eq!(state(&pool), "closed");
+        assert!(pool.allow("a", SETTINGS));
+    }
+
+    #[test]
+    fn failed_trial_reopens_the_breaker() {
+        let pool = tripped();
+        assert!(pool.allow("a", SETTINGS));
+        pool.record_failure("a", SETTINGS);
+        assert_eq!(state(&pool), "open");
+        assert!(!pool.allow("a", SETTINGS));
+    }
+
+    #[test]
+    fn stale_trial_is_retried_after_another_cooldown() {
+        let pool = tripped();
+        assert!(pool.allow("a", SETTINGS));
+        assert!(!pool.allow("a", SETTINGS));
+        // The trial never reported back.
+        cool_down();
+        assert!(pool.allow("a", SETTINGS));
+        assert_eq!(state(&pool), "half_open");
+        assert!(!pool.allow("a", SETTINGS));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let pool = UpstreamPool::default();
+        pool.record_failure("a", SETTINGS);
+        pool.record_success("a");
+        pool.record_failure("a", SETTINGS);
+        assert_eq!(state(&pool), "closed");
+        assert_eq!(pool.breaker_status("a").consecutive_failures, 1);
+    }
+}
@@ -26,7 +26,8 @@ import { TopBar } from './components/TopBar';
 import { IdleAnimation } from './components/IdleAnimation';
 import { SettingsPage } from './pages/SettingsPage';
 import { AgentPage } from './pages/Agent.tsx';
-import type { Task, AppConfig, GenerationGroup } from './types';
+import type { Task, AppConfig, GenerationGroup, ProgressEvent } from './types';
+import { subscribeProgress } from './progress';
 
 // --- Components ---
 
@@ -158,6 +159,9 @@ function GeneratorPage({
                               style={{ width: `${task.progress}%` }}
                             ></div>
                           </div>
+                          {task.stage && (
+                            <p className="mt-4 text-[10px] text-gray-400 dark:text-gray-500 font-mono line-clamp-2 text-center">{task.stage}</p>
+                          )}
                         </div>
                       )}
                     </div>
@@ -327,12 +331,12 @@ function GeneratorPage({
   );
 }
 
-function HistoryPage({ history, onClear }: { history: GenerationGroup[], onClear: () => void }) {
+function HistoryPage({ history, onClear, onDelete }: { history: GenerationGroup[], onClear: () => void, onDelete: (id: string) => void }) {
   const [loadedImages, setLoadedImages] = useState<Set<string>>(new Set());
 
-  const downloadZip = async (timestamp: number) => {
+  const downloadZip = async (id: string, timestamp: number) => {
     toast.promise(
-      axios.get(`/api/export-zip?timestamp=${timestamp}`, { responseType: 'blob' })
+      axios.get(`/api/export-zip?id=${encodeURIComponent(id)}`, { responseType: 'blob' })
         .then(response => {
           const url = window.URL.createObjectURL(new Blob([response.data]));
           const link = document.createElement('a');
@@ -379,20 +383,27 @@ function HistoryPage({ history, onClear }: { history: GenerationGroup[], onClear
       </div>
 
       <div className="space-y-16">
-        {history.map((group, idx) => (
-          <div key={idx} className="group/group">
+        {history.map(group => (
+          <div key={group.id} className="group/group">
             <div className="flex items-center gap-6 mb-6">
               <span className="text-[10px] font-black text-gray-300 dark:text-gray-700 uppercase tracking-[0.3em] whitespace-nowrap">
                 {new Date(group.timestamp).toLocaleString()}
               </span>
               <div className="h-[1px] flex-1 bg-gray-100 dark:bg-white/5"></div>
               <button 
-                onClick={() => downloadZip(group.timestamp)}
+                onClick={() => downloadZip(group.id, group.timestamp)}
                 className="flex items-center gap-2 px-4 py-2 bg-white dark:bg-[#1d1d1f] rounded-xl text-[10px] font-black uppercase tracking-widest text-gray-400 hover:text-black dark:hover:text-white border border-gray-100 dark:border-white/5 shadow-sm transition-all"
               >
                 <Archive className="w-3.5 h-3.5" />
                 打包下载
               </button>
+              <button
+                onClick={() => onDelete(group.id)}
+                className="flex items-center gap-2 px-4 py-2 bg-white dark:bg-[#1d1d1f] rounded-xl text-[10px] font-black uppercase tracking-widest text-gray-400 hover:text-red-600 border border-gray-100 dark:border-white/5 shadow-sm transition-all"
+              >
+                <Trash2 className="w-3.5 h-3.5" />
+                删除
+              </button>
             </div>
             
             <div className="bg-white dark:bg-[#1d1d1f] rounded-[2.5rem] p-8 shadow-[0_8px_30px_rgb(0,0,0,0.02)] border border-gray-50 dark:border-white/5 hover:border-gray-200 dark:hover:border-white/10 transition-all duration-500">
@@ -557,8 +568,9 @@ function AppContent() {
 
   const handleClearHistory = async () => {
     if (window.confirm('确定要清空所有创作历史吗？')) {
+      const purgeFiles = window.confirm('是否同时删除服务器上的图片文件？');
       try {
-        await axios.delete('/api/history');
+        await axios.delete('/api/history', { params: { purge_files: purgeFiles } });
         setHistory([]);
         toast.success('历史已清空');
       } catch (error) {
@@ -567,6 +579,18 @@ function AppContent() {
     }
   };
 
+  const handleDeleteGroup = async (id: string) => {
+    if (window.confirm('确定要删除这组图片吗？图片文件也会被删除。')) {
+      try {
+        await axios.delete(`/api/history/${encodeURIComponent(id)}`);
+        setHistory(prev => prev.filter(group => group.id !== id));
+        toast.success('已删除');
+      } catch (error) {
+        toast.error('删除失败');
+      }
+    }
+  };
+
   const handleUpdateConfig = async (newConfig: AppConfig) => {
     try {
       await axios.post('/api/config', newConfig);
@@ -637,34 +661,64 @@ function AppContent() {
     taskModel: string,
     onFinish: (url?: string) => void
   ) => {
-    setTasks(prev => prev.map(t => t.id === task.id ? { ...t, status: 'generating', progress: 10 } : t));
+    setTasks(prev => prev.map(t => t.id === task.id ? { ...t, status: 'generating', progress: 5, stage: '排队中' } : t));
+
+    // 上游生成期间没有事件，进度在两次事件之间缓慢推进，但不超过当前阶段的上限
+    let ceiling = 10;
+    const progressInterval = setInterval(() => {
+      setTasks(prev => prev.map(t => {
+        if (t.id === task.id && t.status === 'generating' && t.progress < ceiling) {
+          return { ...t, progress: Math.min(ceiling, t.progress + Math.random() * 2) };
+        }
+        return t;
+      }));
+    }, 2000);
+
+    const update = (patch: Partial<Task>) =>
+      setTasks(prev => prev.map(t => t.id === task.id && t.status === 'generating' ? { ...t, ...patch } : t));
+
+    const unsubscribe = subscribeProgress(task.id, (event: ProgressEvent) => {
+      switch (event.event) {
+        case 'attempt_started':
+          ceiling = 80;
+          update({
+            ...(event.attempt === 1 ? { progress: 10 } : {}),
+            stage: `第 ${event.attempt}/${event.max_attempts} 次尝试 · ${event.upstream}`
+          });
+          break;
+        case 'upstream_error':
+          update({ stage: `上游错误: ${event.message}` });
+          break;
+        case 'retrying':
+          update({ stage: `${Math.ceil((event.delay_ms ?? 0) / 1000)} 秒后重试` });
+          break;
+        case 'downloading':
+          ceiling = 95;
+          update({ progress: 85, stage: '正在保存图像' });
+          break;
+        case 'saved':
+          update({ progress: 95, stage: '已保存' });
+          break;
+      }
+    });
 
     try {
-      const progressInterval = setInterval(() => {
-        setTasks(prev => prev.map(t => {
-          if (t.id === task.id && t.status === 'generating' && t.progress < 90) {
-            return { ...t, progress: t.progress + Math.random() * 2 };
-          }
-          return t;
-        }));
-      }, 2000);
-
       const response = await axios.post('/v1/images/generations', {
         prompt: taskPrompt,
         negative_prompt: taskNegative,
         images: taskImages,
         model: taskModel,
         n: 1,
-        size: taskRatio
+        size: taskRatio,
+        task_id: task.id
       });
 
-      clearInterval(progressInterval);
-
       const imageUrl = response.data.data[0].url;
       setTasks(prev => prev.map(t => t.id === task.id ? { 
         ...t, 
         status: 'completed', 
         progress: 100, 
+        stage: undefined,
         resultUrl: imageUrl 
       } : t));
       onFinish(imageUrl);
@@ -672,9 +726,12 @@ function AppContent() {
       setTasks(prev => prev.map(t => t.id === task.id ? {
         ...t,
         status: 'failed',
-        error: error.response?.data || error.message
+        error: error.response?.data?.error?.message || error.message
       } : t));
       onFinish();
+    } finally {
+      clearInterval(progressInterval);
+      unsubscribe();
     }
   };
 
@@ -815,7 +872,7 @@ function AppContent() {
               isDragging={isDragging}
             />
           } />
-          <Route path="/history" element={<HistoryPage history={history} onClear={handleClearHistory} />} />
+          <Route path="/history" element={<HistoryPage history={history} onClear={handleClearHistory} onDelete={handleDeleteGroup} />} />
           <Route path="/settings" element={<SettingsPage config={config} onUpdateConfig={handleUpdateConfig} />} />
           <Route path="/agent" element={<AgentPage isDark={isDark} />} />
         </Routes>
@@ -1,8 +1,32 @@
 import { StrictMode } from 'react'
 import { createRoot } from 'react-dom/client'
+import axios from 'axios'
 import './index.css'
 import App from './App.tsx'
 
+// 管理接口（配置、清空历史）需要 admin_token
+axios.interceptors.request.use((config) => {
+  const token = localStorage.getItem('admin_token');
+  if (token && !config.headers.Authorization) {
+    config.headers.Authorization = `Bearer ${token}`;
+  }
+  return config;
+});
+
+axios.interceptors.response.use(undefined, async (error) => {
+  const original = error.config;
+  if (error.response?.status === 401 && original && !original._authRetried) {
+    const token = window.prompt('请输入管理员令牌 (admin_token)');
+    if (token) {
+      localStorage.setItem('admin_token', token);
+      original._authRetried = true;
+      original.headers.Authorization = `Bearer ${token}`;
+      return axios(original);
+    }
+  }
+  return Promise.reject(error);
+});
+
 createRoot(document.getElementById('root')!).render(
   <StrictMode>
     <App />
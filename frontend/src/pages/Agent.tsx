@@ -23,6 +23,7 @@ import { motion, AnimatePresence } from 'framer-motion';
 import { toast } from 'sonner';
 import { useNavigate } from 'react-router-dom';
 import { Panel, PanelGroup, PanelResizeHandle } from 'react-resizable-panels';
+import { readSseData } from '../sse';
 import { Tldraw, useEditor, createShapeId, AssetRecordType, exportToBlob, toRichText } from 'tldraw';
 import type { TLUiOverrides } from 'tldraw';
 import 'tldraw/tldraw.css';
@@ -300,7 +301,7 @@ export function AgentPage({ isDark = false }: AgentPageProps) {
       }
     } catch (error: any) {
       console.error('生成失败:', error);
-      const errorMsg = error.response?.data || error.message || '未知错误';
+      const errorMsg = error.response?.data?.error?.message || error.message || '未知错误';
       toast.error(`生成失败: ${typeof errorMsg === 'string' ? errorMsg : JSON.stringify(errorMsg)}`, { id: toastId });
     } finally {
       setIsGenerating(false);
@@ -484,23 +485,42 @@ export function AgentPage({ isDark = false }: AgentPageProps) {
     }
 
     try {
-      const response = await axios.post('/api/chat', {
-        messages: newMessages.map(m => ({
-          role: m.role,
-          content: m.content
-        })),
-        model: 'gemini-3-flash'
+      const response = await fetch('/api/chat', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({
+          messages: newMessages.map(m => ({
+            role: m.role,
+            content: m.content
+          })),
+          model: 'gemini-3-flash',
+          stream: true
+        })
+      });
+      if (!response.ok || !response.body) {
+        const data = await response.json().catch(() => null);
+        throw new Error(data?.error?.message || `HTTP ${response.status}`);
+      }
+
+      // 边接收边显示：第一个分片到达时追加助手消息，之后原地更新
+      let assistantContent = '';
+      await readSseData(response.body, data => {
+        if (data === '[DONE]') return;
+        const chunk = JSON.parse(data);
+        if (chunk.error) throw new Error(chunk.error.message);
+        const delta = chunk.choices?.[0]?.delta?.content;
+        if (!delta) return;
+        const isFirst = assistantContent === '';
+        assistantContent += delta;
+        const assistantMessage: Message = { role: 'assistant', content: assistantContent };
+        setMessages(prev => isFirst ? [...prev, assistantMessage] : [...prev.slice(0, -1), assistantMessage]);
       });
 
-      const assistantContent = response.data.choices[0].message.content;
-      const assistantMessage: Message = { role: 'assistant', content: assistantContent };
-      setMessages(prev => [...prev, assistantMessage]);
-      
       // Auto project assistant message to canvas
-      projectToCanvas(assistantContent, 'assistant');
+      if (assistantContent) projectToCanvas(assistantContent, 'assistant');
       
-    } catch (error) {
-      toast.error('发送失败，请检查后端服务及 API 配置');
+    } catch (error: any) {
+      toast.error(error.message ? `发送失败: ${error.message}` : '发送失败，请检查后端服务及 API 配置');
       console.error(error);
     } finally {
       setIsLoading(false);
@@ -591,7 +611,7 @@ export function AgentPage({ isDark = false }: AgentPageProps) {
                     </motion.div>
                   ))}
                 </AnimatePresence>
-                {isLoading && (
+                {isLoading && messages[messages.length - 1]?.role !== 'assistant' && (
                   <div className="flex justify-start">
                     <div className="flex gap-3">
                       <div className="w-8 h-8 rounded-xl bg-white dark:bg-[#1d1d1f] flex items-center justify-center border border-gray-100 dark:border-white/5">
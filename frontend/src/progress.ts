@@ -0,0 +1,22 @@
+import type { ProgressEvent } from './types';
+import { readSseData } from './sse';
+
+// EventSource 不能携带 Authorization 头，这里用 fetch 读取 SSE 流
+export function subscribeProgress(taskId: string, onEvent: (event: ProgressEvent) => void): () => void {
+  const controller = new AbortController();
+  const token = localStorage.getItem('admin_token');
+
+  (async () => {
+    const response = await fetch(`/v1/images/events?task_id=${encodeURIComponent(taskId)}`, {
+      headers: token ? { Authorization: `Bearer ${token}` } : {},
+      signal: controller.signal,
+    });
+    if (!response.ok || !response.body) return;
+
+    await readSseData(response.body, data => onEvent(JSON.parse(data)));
+  })().catch(() => {
+    // 进度只是辅助信息，断开时由生成请求本身的结果兜底
+  });
+
+  return () => controller.abort();
+}
@@ -5,11 +5,28 @@ export interface Task {
   aspect_ratio: string;
   status: 'pending' | 'generating' | 'completed' | 'failed';
   progress: number;
+  stage?: string;
   resultUrl?: string;
   error?: string;
   timestamp: number;
 }
 
+export interface ProgressEvent {
+  seq: number;
+  task_id: string;
+  index?: number;
+  timestamp: number;
+  event: 'queued' | 'attempt_started' | 'upstream_error' | 'retrying' | 'downloading' | 'saved' | 'failed' | 'completed' | 'cancelled';
+  attempt?: number;
+  max_attempts?: number;
+  upstream?: string;
+  delay_ms?: number;
+  url?: string;
+  images?: number;
+  message?: string;
+  code?: string;
+}
+
 export interface AppConfig {
   gemini_proxy_url: string;
   fallback_proxy_url: string | null;
@@ -22,7 +39,27 @@ export interface AppConfig {
 }
 
 export interface GenerationGroup {
+  id: string;
   prompt: string;
   timestamp: number;
   images: string[];
+  client_key_id?: string;
+  source_image?: string;
+  negative_prompt?: string;
+  model?: string;
+  size?: string;
+  references?: string[];
+  mask?: string;
+  upstream?: string;
+  attempts?: number;
+  latency_ms?: number;
+  image_details?: ImageRecord[];
+}
+
+export interface ImageRecord {
+  id: string;
+  url: string;
+  revised_prompt?: string;
+  attempts?: number;
+  latency_ms?: number;
 }